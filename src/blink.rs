@@ -0,0 +1,146 @@
+//! Non-blocking blink patterns
+//!
+//! Blinks an LED, or drives any output pin, following a looping sequence of on / off durations,
+//! e.g. Morse code or status codes. The durations are counted in time outs of a periodic
+//! `CountDown` timer, so `tick` can be called from the main loop or from the timer interrupt
+//! handler.
+
+use hal::digital::OutputPin;
+use hal::timer::CountDown;
+
+/// Output pin driven by a blink pattern
+pub struct BlinkPattern<'a, TIM, PIN> {
+    timer: TIM,
+    pin: PIN,
+    pattern: &'a [u32],
+    step: usize,
+    elapsed: u32,
+}
+
+impl<'a, TIM, PIN> BlinkPattern<'a, TIM, PIN>
+where
+    TIM: CountDown,
+    PIN: OutputPin,
+{
+    /// Drives `pin` following `pattern`, advanced by the time outs of `timer`
+    ///
+    /// `pattern` holds alternating on (high) and off (low) durations, in timer ticks, starting
+    /// with an on duration; it loops once the last duration ends. Zero durations are skipped.
+    ///
+    /// # Panics
+    ///
+    /// If all the durations are zero
+    pub fn new(timer: TIM, pin: PIN, pattern: &'a [u32]) -> Self {
+        assert!(pattern.iter().any(|duration| *duration != 0));
+
+        let mut blink = BlinkPattern {
+            timer,
+            pin,
+            pattern,
+            step: pattern.len() - 1,
+            elapsed: 0,
+        };
+        blink.advance();
+
+        blink
+    }
+
+    /// Accounts for a timer tick, updating the pin if the current duration ended
+    ///
+    /// Does nothing if the timer hasn't timed out yet, so spurious calls are harmless
+    pub fn tick(&mut self) {
+        if self.timer.wait().is_err() {
+            return;
+        }
+
+        self.elapsed += 1;
+        if self.elapsed >= self.pattern[self.step] {
+            self.advance();
+        }
+    }
+
+    /// Moves on to the next non zero duration
+    fn advance(&mut self) {
+        self.elapsed = 0;
+
+        loop {
+            self.step = if self.step + 1 == self.pattern.len() {
+                0
+            } else {
+                self.step + 1
+            };
+
+            if self.pattern[self.step] != 0 {
+                break;
+            }
+        }
+
+        if self.step % 2 == 0 {
+            self.pin.set_high();
+        } else {
+            self.pin.set_low();
+        }
+    }
+
+    /// Releases the timer and the pin
+    pub fn free(self) -> (TIM, PIN) {
+        (self.timer, self.pin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hal::digital::OutputPin;
+    use hal::timer::CountDown;
+    use nb;
+    use void::Void;
+
+    use super::BlinkPattern;
+
+    /// Timer that times out on every `wait`
+    struct Timer;
+
+    impl CountDown for Timer {
+        type Time = ();
+
+        fn start<T>(&mut self, _: T)
+        where
+            T: Into<()>,
+        {
+        }
+
+        fn wait(&mut self) -> nb::Result<(), Void> {
+            Ok(())
+        }
+    }
+
+    struct Pin {
+        high: bool,
+    }
+
+    impl OutputPin for Pin {
+        fn set_high(&mut self) {
+            self.high = true;
+        }
+
+        fn set_low(&mut self) {
+            self.high = false;
+        }
+    }
+
+    #[test]
+    fn pattern_loops() {
+        // on for 2 ticks, off for 1, (skipped), off for 3
+        let pattern = [2, 1, 0, 3];
+        let mut blink = BlinkPattern::new(Timer, Pin { high: false }, &pattern);
+
+        let mut levels = [false; 12];
+        for level in levels.iter_mut() {
+            *level = blink.pin.high;
+            blink.tick();
+        }
+
+        let (t, f) = (true, false);
+        assert_eq!(levels, [t, t, f, f, f, f, t, t, f, f, f, f]);
+    }
+}
@@ -1,24 +1,51 @@
 //! Delays
 
-use cast::u32;
+use core::cmp;
+
+use cast::{u32, u64};
 use cortex_m::peripheral::syst::SystClkSource;
-use cortex_m::peripheral::SYST;
+use cortex_m::peripheral::{DCB, DWT, SYST};
 
 use hal::blocking::delay::{DelayMs, DelayUs};
 use rcc::Clocks;
 
+/// Clock that drives the system timer (SysTick)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClockSource {
+    /// The core clock (HCLK)
+    Core,
+    /// The external reference clock, which on the STM32F30x is HCLK divided by 8
+    External,
+}
+
 /// System timer (SysTick) as a delay provider
 pub struct Delay {
     clocks: Clocks,
+    source: ClockSource,
     syst: SYST,
 }
 
 impl Delay {
-    /// Configures the system timer (SysTick) as a delay provider
-    pub fn new(mut syst: SYST, clocks: Clocks) -> Self {
-        syst.set_clock_source(SystClkSource::Core);
+    /// Configures the system timer (SysTick), clocked by the core clock, as a delay provider
+    pub fn new(syst: SYST, clocks: Clocks) -> Self {
+        Delay::with_clock_source(syst, clocks, ClockSource::Core)
+    }
 
-        Delay { syst, clocks }
+    /// Configures the system timer (SysTick), clocked by `source`, as a delay provider
+    ///
+    /// The external reference clock allows 8 times longer delays per reload but has a coarser
+    /// resolution
+    pub fn with_clock_source(mut syst: SYST, clocks: Clocks, source: ClockSource) -> Self {
+        syst.set_clock_source(match source {
+            ClockSource::Core => SystClkSource::Core,
+            ClockSource::External => SystClkSource::External,
+        });
+
+        Delay {
+            syst,
+            clocks,
+            source,
+        }
     }
 
     /// Releases the system timer (SysTick) resource
@@ -47,11 +74,15 @@ impl DelayMs<u8> for Delay {
 
 impl DelayUs<u32> for Delay {
     fn delay_us(&mut self, us: u32) {
-        let rvr = us * (self.clocks.sysclk().0 / 1_000_000);
+        let rate = match self.source {
+            ClockSource::Core => self.clocks.hclk().0,
+            ClockSource::External => self.clocks.hclk().0 / 8,
+        };
+        let rvr = u64(us) * u64(rate) / 1_000_000;
 
         assert!(rvr < (1 << 24));
 
-        self.syst.set_reload(rvr);
+        self.syst.set_reload(u32(rvr).unwrap());
         self.syst.clear_current();
         self.syst.enable_counter();
 
@@ -72,3 +103,89 @@ impl DelayUs<u8> for Delay {
         self.delay_us(u32(us))
     }
 }
+
+/// Data Watchpoint and Trace (DWT) cycle counter as a delay provider
+///
+/// Busy waits for an exact number of core clock cycles, which gives sub-microsecond resolution.
+/// Requires the DWT unit, which is part of the debug infrastructure of the Cortex-M4 core.
+pub struct CycleDelay {
+    clocks: Clocks,
+    dwt: DWT,
+}
+
+impl CycleDelay {
+    /// Enables the DWT cycle counter and uses it as a delay provider
+    pub fn new(mut dwt: DWT, dcb: &mut DCB, clocks: Clocks) -> Self {
+        // the DWT is only clocked after enabling the trace infrastructure
+        dcb.enable_trace();
+        dwt.enable_cycle_counter();
+
+        CycleDelay { clocks, dwt }
+    }
+
+    /// Busy waits for `cycles` core clock (HCLK) cycles
+    pub fn delay_cycles(&mut self, cycles: u32) {
+        let start = DWT::get_cycle_count();
+
+        while DWT::get_cycle_count().wrapping_sub(start) < cycles {}
+    }
+
+    /// Busy waits for at least `ns` nanoseconds
+    pub fn delay_ns(&mut self, ns: u32) {
+        let hclk = self.clocks.hclk().0;
+        let cycles = (u64(ns) * u64(hclk) + 999_999_999) / 1_000_000_000;
+
+        self.delay_cycles(u32(cycles).unwrap());
+    }
+
+    /// Releases the DWT resource
+    pub fn free(self) -> DWT {
+        self.dwt
+    }
+}
+
+impl DelayMs<u32> for CycleDelay {
+    fn delay_ms(&mut self, ms: u32) {
+        for _ in 0..ms {
+            self.delay_us(1_000_u32);
+        }
+    }
+}
+
+impl DelayMs<u16> for CycleDelay {
+    fn delay_ms(&mut self, ms: u16) {
+        self.delay_ms(u32(ms));
+    }
+}
+
+impl DelayMs<u8> for CycleDelay {
+    fn delay_ms(&mut self, ms: u8) {
+        self.delay_ms(u32(ms));
+    }
+}
+
+impl DelayUs<u32> for CycleDelay {
+    fn delay_us(&mut self, us: u32) {
+        let hclk = self.clocks.hclk().0;
+        let mut cycles = (u64(us) * u64(hclk) + 999_999) / 1_000_000;
+
+        // the cycle count of long delays doesn't fit in 32 bits, so wait in chunks
+        while cycles > 0 {
+            let chunk = cmp::min(cycles, 1 << 31);
+            self.delay_cycles(u32(chunk).unwrap());
+            cycles -= chunk;
+        }
+    }
+}
+
+impl DelayUs<u16> for CycleDelay {
+    fn delay_us(&mut self, us: u16) {
+        self.delay_us(u32(us))
+    }
+}
+
+impl DelayUs<u8> for CycleDelay {
+    fn delay_us(&mut self, us: u8) {
+        self.delay_us(u32(us))
+    }
+}
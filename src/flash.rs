@@ -1,7 +1,11 @@
 //! Flash memory
 
+use core::ptr;
+
 use stm32f30x::{flash, FLASH};
 
+use rcc::wait_ready;
+
 /// Extension trait to constrain the FLASH peripheral
 pub trait FlashExt {
     /// Constrains the FLASH peripheral to play nicely with the other abstractions
@@ -12,6 +16,7 @@ impl FlashExt for FLASH {
     fn constrain(self) -> Parts {
         Parts {
             acr: ACR { _0: () },
+            ob: OB { _0: () },
         }
     }
 }
@@ -20,6 +25,8 @@ impl FlashExt for FLASH {
 pub struct Parts {
     /// Opaque ACR register
     pub acr: ACR,
+    /// Opaque option byte registers
+    pub ob: OB,
 }
 
 /// Opaque ACR register
@@ -28,8 +35,227 @@ pub struct ACR {
 }
 
 impl ACR {
+    /// Creates a new token without going through `FLASH.constrain()`; see the `rcc` module
+    /// documentation
+    #[cfg(feature = "test-util")]
+    pub unsafe fn steal() -> Self {
+        ACR { _0: () }
+    }
+
     pub(crate) fn acr(&mut self) -> &flash::ACR {
         // NOTE(unsafe) this proxy grants exclusive access to this register
         unsafe { &(*FLASH::ptr()).acr }
     }
 }
+
+/// Flash error
+#[derive(Debug)]
+pub enum Error {
+    /// Programming error (PGERR), e.g. the location wasn't erased
+    Programming,
+    /// Write protection error (WRPRTERR)
+    WriteProtection,
+    /// Refused to program read protection level 2, see `OB::program`
+    Level2,
+    /// The operation didn't complete within `BUSY_RETRIES` polls of the BSY flag
+    Timeout,
+    #[doc(hidden)]
+    _Extensible,
+}
+
+/// Read protection (RDP) level
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReadProtection {
+    /// No protection
+    Level0,
+    /// The flash can't be read through the debug interface or when booting from RAM / system
+    /// memory; going back to level 0 mass erases the flash
+    Level1,
+    /// Level 1 plus the debug interface and booting from RAM / system memory are disabled, for
+    /// good
+    Level2,
+}
+
+/// Option bytes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OptionBytes {
+    /// Read protection level
+    pub rdp: ReadProtection,
+    /// User option byte: WDG_SW (bit 0), nRST_STOP (1), nRST_STDBY (2), nBOOT1 (4),
+    /// VDDA_MONITOR (5) and SRAM_PE (6)
+    pub user: u8,
+    /// User data byte 0
+    pub data0: u8,
+    /// User data byte 1
+    pub data1: u8,
+    /// Write protection of the flash pages, as in the WRPR register: a cleared bit protects the
+    /// corresponding group of pages
+    pub wrp: u32,
+}
+
+/// First flash key, for the KEYR and OPTKEYR registers
+const KEY1: u32 = 0x4567_0123;
+/// Second flash key, for the KEYR and OPTKEYR registers
+const KEY2: u32 = 0xcdef_89ab;
+
+/// Number of times the BSY flag is polled before an erase / program operation is considered
+/// stuck
+///
+/// An erase takes up to 40 ms; this leaves a wide margin even with the core running at 72 MHz.
+pub const BUSY_RETRIES: u32 = 4_000_000;
+
+/// Address of the option bytes; each option byte is stored in the low byte of a half word
+const OPTION_BYTES: *mut u16 = 0x1fff_f800 as *mut u16;
+
+/// Opaque option byte registers (CR, KEYR, OPTKEYR, OBR, SR and WRPR)
+pub struct OB {
+    _0: (),
+}
+
+impl OB {
+    fn flash(&mut self) -> &flash::RegisterBlock {
+        // NOTE(unsafe) this proxy grants exclusive access to these registers; ACR is never
+        // touched through it
+        unsafe { &*FLASH::ptr() }
+    }
+
+    /// Returns the option bytes currently in effect
+    ///
+    /// These are the values loaded at the last reset; bytes programmed since then only take
+    /// effect after `reload` or a power cycle.
+    pub fn read(&mut self) -> OptionBytes {
+        let flash = self.flash();
+        let obr = flash.obr.read();
+
+        let rdp = if obr.level2_prot().bit_is_set() {
+            ReadProtection::Level2
+        } else if obr.level1_prot().bit_is_set() {
+            ReadProtection::Level1
+        } else {
+            ReadProtection::Level0
+        };
+
+        OptionBytes {
+            rdp,
+            user: (obr.bits() >> 8) as u8,
+            data0: obr.data0().bits(),
+            data1: obr.data1().bits(),
+            wrp: flash.wrpr.read().bits(),
+        }
+    }
+
+    /// Erases and reprograms all the option bytes
+    ///
+    /// The new values only take effect after `reload` or a power cycle. NOTE if the current
+    /// read protection is level 1, programming level 0 mass erases the whole flash, including
+    /// the running program, when the option bytes are reloaded.
+    ///
+    /// Returns `Error::Level2` without touching the option bytes if `ob.rdp` is level 2: that
+    /// permanently disables the debug interface so it's left to the ST tools.
+    pub fn program(&mut self, ob: &OptionBytes) -> Result<(), Error> {
+        let rdp = match ob.rdp {
+            ReadProtection::Level0 => 0xaa,
+            ReadProtection::Level1 => 0xbb,
+            ReadProtection::Level2 => return Err(Error::Level2),
+        };
+
+        let wrp = ob.wrp;
+        let bytes = [
+            rdp,
+            ob.user,
+            ob.data0,
+            ob.data1,
+            wrp as u8,
+            (wrp >> 8) as u8,
+            (wrp >> 16) as u8,
+            (wrp >> 24) as u8,
+        ];
+
+        self.unlock();
+
+        // NOTE erasing the option bytes sets the read protection to level 1 until RDP is
+        // programmed again below
+        self.flash().cr.modify(|_, w| w.opter().set_bit());
+        self.flash().cr.modify(|_, w| w.strt().set_bit());
+        let result = self.wait();
+        self.flash().cr.modify(|_, w| w.opter().clear_bit());
+
+        let result = result.and_then(|_| {
+            self.flash().cr.modify(|_, w| w.optpg().set_bit());
+
+            let mut result = Ok(());
+            for (i, byte) in bytes.iter().enumerate() {
+                // NOTE(unsafe) the option bytes are mapped and writable while OPTPG is set; the
+                // complement of each byte is computed by the hardware
+                unsafe { ptr::write_volatile(OPTION_BYTES.offset(i as isize), u16::from(*byte)) }
+
+                result = self.wait();
+                if result.is_err() {
+                    break;
+                }
+            }
+
+            self.flash().cr.modify(|_, w| w.optpg().clear_bit());
+
+            result
+        });
+
+        self.lock();
+
+        result
+    }
+
+    /// Loads the option bytes, making the programmed values effective
+    ///
+    /// This resets the device.
+    pub fn reload(&mut self) -> ! {
+        self.unlock();
+        self.flash().cr.modify(|_, w| w.force_optload().set_bit());
+
+        // the reset happens right away
+        loop {}
+    }
+
+    fn unlock(&mut self) {
+        let flash = self.flash();
+
+        if flash.cr.read().lock().bit_is_set() {
+            flash.keyr.write(|w| unsafe { w.bits(KEY1) });
+            flash.keyr.write(|w| unsafe { w.bits(KEY2) });
+        }
+
+        if flash.cr.read().optwre().bit_is_clear() {
+            flash.optkeyr.write(|w| unsafe { w.bits(KEY1) });
+            flash.optkeyr.write(|w| unsafe { w.bits(KEY2) });
+        }
+    }
+
+    fn lock(&mut self) {
+        self.flash()
+            .cr
+            .modify(|_, w| w.optwre().clear_bit().lock().set_bit());
+    }
+
+    /// Waits for the ongoing operation to end and reports its errors
+    fn wait(&mut self) -> Result<(), Error> {
+        let flash = self.flash();
+
+        if wait_ready(|| flash.sr.read().bsy().bit_is_clear(), BUSY_RETRIES).is_err() {
+            return Err(Error::Timeout);
+        }
+
+        let sr = flash.sr.read();
+        let result = if sr.wrprt().bit_is_set() {
+            Err(Error::WriteProtection)
+        } else if sr.pgerr().bit_is_set() {
+            Err(Error::Programming)
+        } else {
+            Ok(())
+        };
+
+        // clear EOP, WRPRTERR and PGERR, which are cleared by writing 1
+        flash.sr.write(|w| unsafe { w.bits(0b11_0100) });
+
+        result
+    }
+}
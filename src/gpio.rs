@@ -1,10 +1,31 @@
 //! General Purpose Input / Output
+//!
+//! # Reconfiguring pins from several contexts
+//!
+//! Changing the mode of a pin is a read-modify-write of the port-wide MODER, OSPEEDR, OTYPER,
+//! PUPDR and AFR registers. Those registers can only be reached through the `&mut` proxies
+//! (`MODER`, `OSPEEDR`, `OTYPER`, `PUPDR`, `AFRL`, `AFRH`) handed out by `split`, so two contexts
+//! can't reconfigure pins of the same port concurrently: to reconfigure pins from both `main` and
+//! an interrupt handler the proxies have to be moved into a `cortex_m::interrupt::Mutex`, which
+//! already serializes the accesses. No additional locking is done by the `into_*` methods.
+//!
+//! Driving an output (`set_high` / `set_low`) writes to the BSRR register, which is atomic, and
+//! reading an input only reads IDR, so neither of them needs the proxies nor a critical section.
+//!
+//! # Debug pins
+//!
+//! PA13 (SWDIO) and PA14 (SWCLK) carry the SWD connection to the debugger, and PA15 (JTDI), PB3
+//! (JTDO / SWO) and PB4 (NJTRST) complete the JTAG port. They come out of reset in AF0, which is
+//! how `split` hands them out. Reconfiguring PA13 or PA14, or powering down port A, cuts off an
+//! attached SWD debugger until the next reset. Reconfiguring the other three breaks JTAG and, for
+//! PB3, the SWO trace output.
 
 // TODO the pins here currently correspond to the LQFP-100 package. There should be Cargo features
 // that let you select different microcontroller packages
 
 use core::marker::PhantomData;
 
+use hal::digital::{toggleable, OutputPin, StatefulOutputPin};
 use rcc::AHB;
 
 /// Extension trait to split a GPIO peripheral in independent pins and registers
@@ -16,6 +37,107 @@ pub trait GpioExt {
     fn split(self, ahb: &mut AHB) -> Self::Parts;
 }
 
+/// GPIO error
+#[derive(Debug)]
+pub enum Error {
+    /// The pin doesn't exist on the target package
+    NoSuchPin,
+    #[doc(hidden)]
+    _Extensible,
+}
+
+/// GPIO port
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Port {
+    /// GPIOA
+    A,
+    /// GPIOB
+    B,
+    /// GPIOC
+    C,
+    /// GPIOD
+    D,
+    /// GPIOE
+    E,
+    /// GPIOF
+    F,
+}
+
+/// Output slew rate
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Speed {
+    /// Low speed (up to 2 MHz)
+    Low,
+    /// Medium speed (up to 10 MHz)
+    Medium,
+    /// High speed (up to 50 MHz)
+    High,
+}
+
+impl Speed {
+    fn bits(&self) -> u32 {
+        match *self {
+            Speed::Low => 0b00,
+            Speed::Medium => 0b01,
+            Speed::High => 0b11,
+        }
+    }
+}
+
+/// Runtime description of a pin, e.g. as loaded from a board configuration table
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AnyPin {
+    port: Port,
+    i: u8,
+}
+
+impl AnyPin {
+    /// Describes pin number `i` of `port`
+    ///
+    /// Returns an error if the pin is not available on the target package
+    pub fn new(port: Port, i: u8) -> Result<Self, Error> {
+        let pins = match port {
+            Port::A => gpioa::PINS,
+            Port::B => gpiob::PINS,
+            Port::C => gpioc::PINS,
+            Port::D => gpiod::PINS,
+            Port::E => gpioe::PINS,
+            Port::F => gpiof::PINS,
+        };
+
+        if i < 16 && pins & (1 << i) != 0 {
+            Ok(AnyPin { port, i })
+        } else {
+            Err(Error::NoSuchPin)
+        }
+    }
+
+    /// Describes pin number `i` of the port named `port` (`"PA"`, `"PB"`, etc.)
+    pub fn parse(port: &str, i: u8) -> Result<Self, Error> {
+        let port = match port {
+            "PA" => Port::A,
+            "PB" => Port::B,
+            "PC" => Port::C,
+            "PD" => Port::D,
+            "PE" => Port::E,
+            "PF" => Port::F,
+            _ => return Err(Error::NoSuchPin),
+        };
+
+        AnyPin::new(port, i)
+    }
+
+    /// Returns the port this pin belongs to
+    pub fn port(&self) -> Port {
+        self.port
+    }
+
+    /// Returns the pin number
+    pub fn index(&self) -> u8 {
+        self.i
+    }
+}
+
 /// Input mode (type state)
 pub struct Input<MODE> {
     _mode: PhantomData<MODE>,
@@ -38,6 +160,102 @@ pub struct PushPull;
 /// Open drain output (type state)
 pub struct OpenDrain;
 
+/// Analog mode (type state)
+///
+/// In this mode the digital input buffer (Schmitt trigger) of the pin is disconnected, so it
+/// neither loads the analog signal nor draws current on intermediate voltage levels
+pub struct Analog;
+
+/// Output pin with inverted logic, for active low loads like LEDs wired to VDD or relay drivers
+///
+/// `set_high` drives the pin low, `set_low` drives it high and the `is_set_*` methods report the
+/// logical state, so "high" always means "on"
+pub struct ActiveLow<PIN> {
+    pin: PIN,
+}
+
+impl<PIN> ActiveLow<PIN>
+where
+    PIN: OutputPin,
+{
+    /// Inverts the logic of `pin`; see also the `into_active_low` method of the output pins
+    pub fn new(pin: PIN) -> Self {
+        ActiveLow { pin }
+    }
+
+    /// Releases the pin
+    pub fn free(self) -> PIN {
+        self.pin
+    }
+}
+
+impl<PIN> OutputPin for ActiveLow<PIN>
+where
+    PIN: OutputPin,
+{
+    fn set_high(&mut self) {
+        self.pin.set_low()
+    }
+
+    fn set_low(&mut self) {
+        self.pin.set_high()
+    }
+}
+
+impl<PIN> StatefulOutputPin for ActiveLow<PIN>
+where
+    PIN: OutputPin + StatefulOutputPin,
+{
+    fn is_set_high(&self) -> bool {
+        self.pin.is_set_low()
+    }
+
+    fn is_set_low(&self) -> bool {
+        self.pin.is_set_high()
+    }
+}
+
+impl<PIN> toggleable::Default for ActiveLow<PIN> where PIN: OutputPin + StatefulOutputPin {}
+
+/// Pull resistor configuration of an input (see `PullProbe`)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pull {
+    /// No pull resistor
+    Floating,
+    /// Pull-up resistor
+    Up,
+    /// Pull-down resistor
+    Down,
+}
+
+impl Pull {
+    fn bits(&self) -> u32 {
+        match *self {
+            Pull::Floating => 0b00,
+            Pull::Up => 0b01,
+            Pull::Down => 0b10,
+        }
+    }
+}
+
+/// Returns the MODER value `moder` with pin `i` switched to `mode` (0b00: input, 0b01: output,
+/// 0b10: alternate function, 0b11: analog)
+fn moder_bits(moder: u32, i: u8, mode: u32) -> u32 {
+    let offset = 2 * i;
+    (moder & !(0b11 << offset)) | (mode << offset)
+}
+
+/// Returns the alternate function pin `i` is programmed to in `afrl` / `afrh`, or `None` if
+/// `moder` doesn't put it in alternate function mode (0b10)
+fn alternate_function(moder: u32, afrl: u32, afrh: u32, i: u8) -> Option<u8> {
+    if (moder >> (2 * i)) & 0b11 != 0b10 {
+        return None;
+    }
+
+    let afr = if i < 8 { afrl } else { afrh };
+    Some(((afr >> (4 * (i % 8))) & 0b1111) as u8)
+}
+
 /// Alternate function 0 (type state)
 pub struct AF0;
 
@@ -86,37 +304,141 @@ pub struct AF14;
 /// Alternate function 15 (type state)
 pub struct AF15;
 
+/// Alternate function type states
+pub trait AltFn {
+    #[doc(hidden)]
+    const NUMBER: u32;
+}
+
+impl AltFn for AF0 {
+    const NUMBER: u32 = 0;
+}
+
+impl AltFn for AF1 {
+    const NUMBER: u32 = 1;
+}
+
+impl AltFn for AF2 {
+    const NUMBER: u32 = 2;
+}
+
+impl AltFn for AF3 {
+    const NUMBER: u32 = 3;
+}
+
+impl AltFn for AF4 {
+    const NUMBER: u32 = 4;
+}
+
+impl AltFn for AF5 {
+    const NUMBER: u32 = 5;
+}
+
+impl AltFn for AF6 {
+    const NUMBER: u32 = 6;
+}
+
+impl AltFn for AF7 {
+    const NUMBER: u32 = 7;
+}
+
+impl AltFn for AF8 {
+    const NUMBER: u32 = 8;
+}
+
+impl AltFn for AF9 {
+    const NUMBER: u32 = 9;
+}
+
+impl AltFn for AF10 {
+    const NUMBER: u32 = 10;
+}
+
+impl AltFn for AF11 {
+    const NUMBER: u32 = 11;
+}
+
+impl AltFn for AF12 {
+    const NUMBER: u32 = 12;
+}
+
+impl AltFn for AF13 {
+    const NUMBER: u32 = 13;
+}
+
+impl AltFn for AF14 {
+    const NUMBER: u32 = 14;
+}
+
+impl AltFn for AF15 {
+    const NUMBER: u32 = 15;
+}
+
+macro_rules! alt_pins {
+    ($($P:ident: $p:ident),+) => {
+        impl<AF, $($P),+> AltPins<AF> for ($($P,)+)
+        where
+            AF: AltFn,
+            $($P: GroupPin<AF>,)+
+        {
+            type Output = ($($P::Output,)+);
+
+            fn into_alternate(
+                self,
+                moder: &mut MODER,
+                afrl: &mut AFRL,
+                afrh: &mut AFRH,
+            ) -> Self::Output {
+                into_alternate(0 $(| 1 << $P::I)+, AF::NUMBER, moder, afrl, afrh);
+
+                let ($($p,)+) = self;
+                // NOTE(unsafe) the pins have just been configured in the `AF` mode
+                unsafe { ($($p.into_group_mode(),)+) }
+            }
+        }
+    }
+}
+
 macro_rules! gpio {
-    ($GPIOX:ident, $gpiox:ident, $gpioy:ident, $iopxenr:ident, $iopxrst:ident, $PXx:ident, [
-        $($PXi:ident: ($pxi:ident, $i:expr, $MODE:ty, $AFR:ident),)+
+    ($GPIOX:ident, $gpiox:ident, $gpioy:ident, $iopxenr:ident, $iopxrst:ident, $PXx:ident, $Port:ident, [
+        $($(#[$doc:meta])* $PXi:ident: ($pxi:ident, $i:expr, $MODE:ty, $AFR:ident),)+
     ]) => {
         /// GPIO
         pub mod $gpiox {
             use core::marker::PhantomData;
 
-            use hal::digital::OutputPin;
+            use hal::digital::{toggleable, InputPin, OutputPin, StatefulOutputPin};
             use stm32f30x::{$gpioy, $GPIOX};
 
             use rcc::AHB;
             use super::{
-                AF4, AF5, AF6, AF7, Floating, GpioExt, Input, OpenDrain, Output,
-                PullDown, PullUp, PushPull,
+                AF0, AF4, AF5, AF6, AF7, ActiveLow, AltFn, Analog, AnyPin, Floating, GpioExt, Input,
+                OpenDrain, Output, Port, Pull, PullDown, PullUp, PushPull, Speed,
+                alternate_function, moder_bits,
             };
 
+            /// Mask of the pins available on this port
+            pub(crate) const PINS: u16 = 0 $(| 1 << $i)+;
+
             /// GPIO parts
             pub struct Parts {
                 /// Opaque AFRH register
                 pub afrh: AFRH,
                 /// Opaque AFRL register
                 pub afrl: AFRL,
+                /// Opaque IDR and ODR registers
+                pub data: DATA,
                 /// Opaque MODER register
                 pub moder: MODER,
+                /// Opaque OSPEEDR register
+                pub ospeedr: OSPEEDR,
                 /// Opaque OTYPER register
                 pub otyper: OTYPER,
                 /// Opaque PUPDR register
                 pub pupdr: PUPDR,
                 $(
                     /// Pin
+                    $(#[$doc])*
                     pub $pxi: $PXi<$MODE>,
                 )+
             }
@@ -124,6 +446,188 @@ macro_rules! gpio {
             impl GpioExt for $GPIOX {
                 type Parts = Parts;
 
+                fn split(self, ahb: &mut AHB) -> Parts {
+                    Disabled { _0: () }.split(ahb)
+                }
+            }
+
+            impl Parts {
+                /// Puts every pin of the port in analog mode, its lowest power state, and, if
+                /// `disable_clock` is `true`, stops the port clock
+                ///
+                /// All the pins are consumed. To use the port again call `split` on the returned
+                /// token, which re-enables and resets the port. NOTE on port A this includes
+                /// the SWD pins (PA13 and PA14) so a debugger will lose the connection.
+                pub fn power_down(mut self, ahb: &mut AHB, disable_clock: bool) -> Disabled {
+                    let mask = 0 $(| 0b11 << (2 * $i))+;
+
+                    self.pupdr
+                        .pupdr()
+                        .modify(|r, w| unsafe { w.bits(r.bits() & !mask) });
+                    self.moder
+                        .moder()
+                        .modify(|r, w| unsafe { w.bits(r.bits() | mask) });
+
+                    if disable_clock {
+                        ahb.enr().modify(|_, w| w.$iopxenr().disabled());
+                    }
+
+                    Disabled { _0: () }
+                }
+
+                /// Splits the port into its pins and a `Config` that owns all the register proxies
+                ///
+                /// The pins can then be configured without passing the registers around, e.g.
+                /// `let led = cfg.push_pull_output(pins.pe9);`
+                pub fn configure(self) -> (Config, Pins) {
+                    (
+                        Config {
+                            afrh: self.afrh,
+                            afrl: self.afrl,
+                            moder: self.moder,
+                            ospeedr: self.ospeedr,
+                            otyper: self.otyper,
+                            pupdr: self.pupdr,
+                        },
+                        Pins {
+                            data: self.data,
+                            $(
+                                $pxi: self.$pxi,
+                            )+
+                        },
+                    )
+                }
+            }
+
+            /// The pins of the port (see `Parts::configure`)
+            pub struct Pins {
+                /// Opaque IDR and ODR registers
+                pub data: DATA,
+                $(
+                    /// Pin
+                    $(#[$doc])*
+                    pub $pxi: $PXi<$MODE>,
+                )+
+            }
+
+            /// Owner of all the configuration registers of the port (see `Parts::configure`)
+            pub struct Config {
+                afrh: AFRH,
+                afrl: AFRL,
+                moder: MODER,
+                ospeedr: OSPEEDR,
+                otyper: OTYPER,
+                pupdr: PUPDR,
+            }
+
+            impl Config {
+                /// Configures `pin` as a floating input
+                pub fn floating_input<P>(&mut self, pin: P) -> P::FloatingInput
+                where
+                    P: ConfigPin,
+                {
+                    pin.floating_input(self)
+                }
+
+                /// Configures `pin` as a pulled down input
+                pub fn pull_down_input<P>(&mut self, pin: P) -> P::PullDownInput
+                where
+                    P: ConfigPin,
+                {
+                    pin.pull_down_input(self)
+                }
+
+                /// Configures `pin` as a pulled up input
+                pub fn pull_up_input<P>(&mut self, pin: P) -> P::PullUpInput
+                where
+                    P: ConfigPin,
+                {
+                    pin.pull_up_input(self)
+                }
+
+                /// Configures `pin` as an open drain output
+                pub fn open_drain_output<P>(&mut self, pin: P) -> P::OpenDrainOutput
+                where
+                    P: ConfigPin,
+                {
+                    pin.open_drain_output(self)
+                }
+
+                /// Configures `pin` as a push pull output
+                pub fn push_pull_output<P>(&mut self, pin: P) -> P::PushPullOutput
+                where
+                    P: ConfigPin,
+                {
+                    pin.push_pull_output(self)
+                }
+
+                /// Configures `pin` in analog mode
+                pub fn analog<P>(&mut self, pin: P) -> P::Analog
+                where
+                    P: ConfigPin,
+                {
+                    pin.analog(self)
+                }
+
+                /// Configures a group of pins, e.g. `(pins.pa5, pins.pa6, pins.pa7)`, to serve as
+                /// alternate function `AF`
+                pub fn alternate<P, AF>(&mut self, pins: P) -> P::Output
+                where
+                    P: AltPins<AF>,
+                {
+                    pins.into_alternate(&mut self.moder, &mut self.afrl, &mut self.afrh)
+                }
+
+                /// Sets the output speed of `pin`
+                pub fn set_speed<P>(&mut self, pin: &mut P, speed: Speed)
+                where
+                    P: ConfigPin,
+                {
+                    pin.set_speed(self, speed)
+                }
+            }
+
+            // FIXME these should be "closed" traits
+            /// Pin of this port that can be configured through `Config` -- DO NOT IMPLEMENT THIS
+            /// TRAIT
+            pub unsafe trait ConfigPin {
+                /// The pin configured as a floating input
+                type FloatingInput;
+                /// The pin configured as a pulled down input
+                type PullDownInput;
+                /// The pin configured as a pulled up input
+                type PullUpInput;
+                /// The pin configured as an open drain output
+                type OpenDrainOutput;
+                /// The pin configured as a push pull output
+                type PushPullOutput;
+                /// The pin configured in analog mode
+                type Analog;
+
+                #[doc(hidden)]
+                fn floating_input(self, cfg: &mut Config) -> Self::FloatingInput;
+                #[doc(hidden)]
+                fn pull_down_input(self, cfg: &mut Config) -> Self::PullDownInput;
+                #[doc(hidden)]
+                fn pull_up_input(self, cfg: &mut Config) -> Self::PullUpInput;
+                #[doc(hidden)]
+                fn open_drain_output(self, cfg: &mut Config) -> Self::OpenDrainOutput;
+                #[doc(hidden)]
+                fn push_pull_output(self, cfg: &mut Config) -> Self::PushPullOutput;
+                #[doc(hidden)]
+                fn analog(self, cfg: &mut Config) -> Self::Analog;
+                #[doc(hidden)]
+                fn set_speed(&mut self, cfg: &mut Config, speed: Speed);
+            }
+
+            /// A port that has been powered down (see `Parts::power_down`)
+            pub struct Disabled {
+                _0: (),
+            }
+
+            impl GpioExt for Disabled {
+                type Parts = Parts;
+
                 fn split(self, ahb: &mut AHB) -> Parts {
                     ahb.enr().modify(|_, w| w.$iopxenr().enabled());
                     ahb.rstr().modify(|_, w| w.$iopxrst().set_bit());
@@ -132,7 +636,9 @@ macro_rules! gpio {
                     Parts {
                         afrh: AFRH { _0: () },
                         afrl: AFRL { _0: () },
+                        data: DATA { _0: () },
                         moder: MODER { _0: () },
+                        ospeedr: OSPEEDR { _0: () },
                         otyper: OTYPER { _0: () },
                         pupdr: PUPDR { _0: () },
                         $(
@@ -164,6 +670,111 @@ macro_rules! gpio {
                 }
             }
 
+            fn bsrr_ptr_and_mask(i: u8) -> (*mut u32, u32) {
+                // NOTE(unsafe) only the address of the register is computed
+                let bsrr = unsafe { &(*$GPIOX::ptr()).bsrr as *const _ as *mut u32 };
+
+                (bsrr, 1 << i)
+            }
+
+            fn read_alternate_function(i: u8) -> Option<u8> {
+                // NOTE(unsafe) atomic reads with no side effects
+                unsafe {
+                    let gpio = &*$GPIOX::ptr();
+
+                    alternate_function(
+                        gpio.moder.read().bits(),
+                        gpio.afrl.read().bits(),
+                        gpio.afrh.read().bits(),
+                        i,
+                    )
+                }
+            }
+
+            /// Opaque IDR and ODR registers, to access all the pins of the port at once
+            ///
+            /// NOTE these accesses bypass the type state of the pin handles: `write_port` also
+            /// changes the output level of pins that are owned by other drivers, and `read_port`
+            /// reports the level of every pin whatever its mode. Use them with pins that have all
+            /// been configured for that purpose, e.g. the lines of a parallel bus.
+            pub struct DATA {
+                _0: (),
+            }
+
+            impl DATA {
+                /// Returns the input level of all the pins of the port (IDR); bit `i` is pin `i`
+                pub fn read_port(&self) -> u16 {
+                    // NOTE(unsafe) atomic read with no side effects
+                    unsafe { (*$GPIOX::ptr()).idr.read().bits() as u16 }
+                }
+
+                /// Sets the output level of all the pins of the port; bit `i` is pin `i`
+                ///
+                /// This goes through BSRR so all the pins change in one atomic write
+                pub fn write_port(&mut self, value: u16) {
+                    let bits = u32::from(value) | (u32::from(!value) << 16);
+
+                    // NOTE(unsafe) atomic write to a stateless register
+                    unsafe { (*$GPIOX::ptr()).bsrr.write(|w| w.bits(bits)) }
+                }
+            }
+
+            /// Input pin whose pull resistor can be switched between reads
+            ///
+            /// For probing what's connected to a line, e.g. telling a floating line from one that's
+            /// driven: read it once with the pull-up (`with_pull(Pull::Up)`) and once with the
+            /// pull-down. NOTE the line takes some time to settle after the pull changes: the
+            /// internal resistors are about 40 KOhm so allow a few microseconds, more if the line
+            /// has external capacitance, before calling `read`. The original pull configuration is
+            /// restored by `free`.
+            pub struct PullProbe<'a, PIN> {
+                pin: PIN,
+                i: u8,
+                original: u32,
+                pupdr: &'a mut PUPDR,
+            }
+
+            impl<'a, PIN> PullProbe<'a, PIN>
+            where
+                PIN: InputPin,
+            {
+                /// Switches the pull resistor of the pin to `pull`
+                pub fn with_pull(&mut self, pull: Pull) -> &mut Self {
+                    self.set_pull_bits(pull.bits());
+                    self
+                }
+
+                /// Switches to the pull-up resistor
+                pub fn with_pull_up(&mut self) -> &mut Self {
+                    self.with_pull(Pull::Up)
+                }
+
+                /// Switches to the pull-down resistor
+                pub fn with_pull_down(&mut self) -> &mut Self {
+                    self.with_pull(Pull::Down)
+                }
+
+                /// Returns `true` if the line is high
+                pub fn read(&self) -> bool {
+                    self.pin.is_high()
+                }
+
+                /// Restores the original pull configuration and releases the pin
+                pub fn free(mut self) -> PIN {
+                    let original = self.original;
+                    self.set_pull_bits(original);
+                    self.pin
+                }
+
+                fn set_pull_bits(&mut self, bits: u32) {
+                    let offset = 2 * self.i;
+
+                    self.pupdr.pupdr().modify(|r, w| unsafe {
+                        w.bits((r.bits() & !(0b11 << offset)) | (bits << offset))
+                    });
+                }
+            }
+
             /// Opaque MODER register
             pub struct MODER {
                 _0: (),
@@ -175,6 +786,17 @@ macro_rules! gpio {
                 }
             }
 
+            /// Opaque OSPEEDR register
+            pub struct OSPEEDR {
+                _0: (),
+            }
+
+            impl OSPEEDR {
+                pub(crate) fn ospeedr(&mut self) -> &$gpioy::OSPEEDR {
+                    unsafe { &(*$GPIOX::ptr()).ospeedr }
+                }
+            }
+
             /// Opaque OTYPER register
             pub struct OTYPER {
                 _0: (),
@@ -197,12 +819,133 @@ macro_rules! gpio {
                 }
             }
 
+            /// Pin of this port that can be configured as part of a group (see `AltPins`)
+            pub unsafe trait GroupPin<AF> {
+                #[doc(hidden)]
+                const I: u8;
+
+                /// The pin configured in alternate function `AF`
+                type Output;
+
+                #[doc(hidden)]
+                unsafe fn into_group_mode(self) -> Self::Output;
+            }
+
+            /// Group of pins of this port that can be configured as the same alternate function
+            /// in one go
+            ///
+            /// This is implemented for tuples of up to 8 pins and returns a tuple of the
+            /// configured pins, in the same order. The AFRL and AFRH registers are only written
+            /// to if the group contains pins in the 0-7 and 8-15 ranges, respectively, so a group
+            /// that spans both halves of the port costs one extra register write.
+            pub trait AltPins<AF> {
+                /// The configured pins
+                type Output;
+
+                /// Configures all the pins to serve as alternate function `AF`
+                fn into_alternate(
+                    self,
+                    moder: &mut MODER,
+                    afrl: &mut AFRL,
+                    afrh: &mut AFRH,
+                ) -> Self::Output;
+            }
+
+            fn into_alternate(
+                pins: u16,
+                af: u32,
+                moder: &mut MODER,
+                afrl: &mut AFRL,
+                afrh: &mut AFRH,
+            ) {
+                let (mut mode_mask, mut mode) = (0, 0);
+                let (mut afrl_mask, mut afrl_bits) = (0, 0);
+                let (mut afrh_mask, mut afrh_bits) = (0, 0);
+                for i in 0..16 {
+                    if pins & (1 << i) != 0 {
+                        mode_mask |= 0b11 << (2 * i);
+                        mode |= 0b10 << (2 * i);
+
+                        let offset = 4 * (i % 8);
+                        if i < 8 {
+                            afrl_mask |= 0b1111 << offset;
+                            afrl_bits |= af << offset;
+                        } else {
+                            afrh_mask |= 0b1111 << offset;
+                            afrh_bits |= af << offset;
+                        }
+                    }
+                }
+
+                // NOTE select the alternate function before switching the pins to alternate
+                // function mode
+                if afrl_mask != 0 {
+                    afrl.afr().modify(|r, w| unsafe {
+                        w.bits((r.bits() & !afrl_mask) | afrl_bits)
+                    });
+                }
+
+                if afrh_mask != 0 {
+                    afrh.afr().modify(|r, w| unsafe {
+                        w.bits((r.bits() & !afrh_mask) | afrh_bits)
+                    });
+                }
+
+                moder
+                    .moder()
+                    .modify(|r, w| unsafe { w.bits((r.bits() & !mode_mask) | mode) });
+            }
+
+            alt_pins!(A: a);
+            alt_pins!(A: a, B: b);
+            alt_pins!(A: a, B: b, C: c);
+            alt_pins!(A: a, B: b, C: c, D: d);
+            alt_pins!(A: a, B: b, C: c, D: d, E: e);
+            alt_pins!(A: a, B: b, C: c, D: d, E: e, F: f);
+            alt_pins!(A: a, B: b, C: c, D: d, E: e, F: f, G: g);
+            alt_pins!(A: a, B: b, C: c, D: d, E: e, F: f, G: g, H: h);
+
             /// Partially erased pin
             pub struct $PXx<MODE> {
                 i: u8,
                 _mode: PhantomData<MODE>,
             }
 
+            impl<MODE> $PXx<MODE> {
+                /// Creates the partially erased pin described by `pin`
+                ///
+                /// Returns `None` if `pin` doesn't belong to this port
+                ///
+                /// # Safety
+                ///
+                /// The caller must own the pin (it must not be in use through any other handle)
+                /// and the pin must already be configured in `MODE`
+                pub unsafe fn from_any(pin: AnyPin) -> Option<Self> {
+                    if pin.port() == Port::$Port {
+                        Some($PXx {
+                            i: pin.index(),
+                            _mode: PhantomData,
+                        })
+                    } else {
+                        None
+                    }
+                }
+
+                /// Returns the runtime description of this pin
+                pub fn pin(&self) -> AnyPin {
+                    AnyPin {
+                        port: Port::$Port,
+                        i: self.i,
+                    }
+                }
+
+                /// Returns the alternate function the pin is currently programmed to, or `None`
+                /// if it's not in alternate function mode
+                pub fn alternate_function(&self) -> Option<u8> {
+                    read_alternate_function(self.i)
+                }
+            }
+
             impl<MODE> OutputPin for $PXx<Output<MODE>> {
                 fn set_high(&mut self) {
                     // NOTE(unsafe) atomic write to a stateless register
@@ -215,26 +958,159 @@ macro_rules! gpio {
                 }
             }
 
+            impl<MODE> StatefulOutputPin for $PXx<Output<MODE>> {
+                fn is_set_high(&self) -> bool {
+                    !self.is_set_low()
+                }
+
+                fn is_set_low(&self) -> bool {
+                    // NOTE(unsafe) atomic read with no side effects
+                    unsafe { (*$GPIOX::ptr()).odr.read().bits() & (1 << self.i) == 0 }
+                }
+            }
+
+            impl<MODE> toggleable::Default for $PXx<Output<MODE>> {}
+
+            impl<MODE> $PXx<Output<MODE>> {
+                /// Inverts the logic of the pin, see `ActiveLow`
+                pub fn into_active_low(self) -> ActiveLow<Self> {
+                    ActiveLow::new(self)
+                }
+
+                /// Returns the address of the port BSRR register and the bit mask of this pin
+                ///
+                /// # Safety
+                ///
+                /// Same rules as for the non erased pins; see e.g. `PA0::port_ptr_and_mask`
+                pub unsafe fn port_ptr_and_mask(&self) -> (*mut u32, u32) {
+                    bsrr_ptr_and_mask(self.i)
+                }
+            }
+
+            impl $PXx<Output<OpenDrain>> {
+                /// Returns `true` if the line is actually high, i.e. the pin is released and
+                /// nothing else is pulling the line low
+                pub fn input_is_high(&self) -> bool {
+                    !self.input_is_low()
+                }
+
+                /// Returns `true` if the line is actually low, either because this pin or
+                /// something else is driving it low
+                pub fn input_is_low(&self) -> bool {
+                    // NOTE(unsafe) atomic read with no side effects
+                    unsafe { (*$GPIOX::ptr()).idr.read().bits() & (1 << self.i) == 0 }
+                }
+            }
+
+            impl InputPin for $PXx<Output<OpenDrain>> {
+                fn is_high(&self) -> bool {
+                    self.input_is_high()
+                }
+
+                fn is_low(&self) -> bool {
+                    self.input_is_low()
+                }
+            }
+
+            impl<MODE> InputPin for $PXx<Input<MODE>> {
+                fn is_high(&self) -> bool {
+                    !self.is_low()
+                }
+
+                fn is_low(&self) -> bool {
+                    // NOTE(unsafe) atomic read with no side effects
+                    unsafe { (*$GPIOX::ptr()).idr.read().bits() & (1 << self.i) == 0 }
+                }
+            }
+
             $(
                 /// Pin
+                $(#[$doc])*
                 pub struct $PXi<MODE> {
                     _mode: PhantomData<MODE>,
                 }
 
+                impl $PXi<Output<PushPull>> {
+                    /// Enables the port and configures only this pin as a push pull output
+                    ///
+                    /// This is a shortcut for examples and quick tests: the GPIO peripheral is
+                    /// consumed so the other pins of the port can't be used. Use `split` instead
+                    /// to use several pins of the port
+                    pub fn new_output(gpio: $GPIOX, ahb: &mut AHB) -> Self {
+                        let mut parts = gpio.split(ahb);
+
+                        parts
+                            .$pxi
+                            .into_push_pull_output(&mut parts.moder, &mut parts.otyper)
+                    }
+                }
+
+                impl<AF> $PXi<AF>
+                where
+                    AF: AltFn,
+                {
+                    /// Selects an open drain (`true`) or push pull (`false`) output driver for the
+                    /// alternate function
+                    pub fn set_open_drain(&mut self, otyper: &mut OTYPER, open_drain: bool) {
+                        let bit = if open_drain { 1 } else { 0 };
+                        otyper.otyper().modify(|r, w| unsafe {
+                            w.bits((r.bits() & !(0b1 << $i)) | (bit << $i))
+                        });
+                    }
+                }
+
                 impl<MODE> $PXi<MODE> {
+                    /// Returns the alternate function the pin is currently programmed to, or
+                    /// `None` if it's not in alternate function mode
+                    pub fn alternate_function(&self) -> Option<u8> {
+                        read_alternate_function($i)
+                    }
+
+                    /// Sets the output slew rate of the pin, whatever its mode
+                    ///
+                    /// Pins come out of reset at `Speed::Low`, which only suits signals up to 2
+                    /// MHz; e.g. the SCK and MOSI pins of an SPI bus clocked above a few MHz need
+                    /// `Speed::High` (the fastest setting on this family) after `into_af5`
+                    pub fn set_speed(&mut self, ospeedr: &mut OSPEEDR, speed: Speed) {
+                        let offset = 2 * $i;
+                        let speed = speed.bits();
+                        ospeedr.ospeedr().modify(|r, w| unsafe {
+                            w.bits((r.bits() & !(0b11 << offset)) | (speed << offset))
+                        });
+                    }
+
+                    /// Configures the pin to serve as alternate function 0 (AF0)
+                    pub fn into_af0(
+                        self,
+                        moder: &mut MODER,
+                        afr: &mut $AFR,
+                    ) -> $PXi<AF0> {
+                        // alternate function mode
+                        let mode = 0b10;
+                        moder
+                            .moder()
+                            .modify(|r, w| unsafe { w.bits(moder_bits(r.bits(), $i, mode)) });
+
+                        let af = 0;
+                        let offset = 4 * ($i % 8);
+                        afr.afr().modify(|r, w| unsafe {
+                            w.bits((r.bits() & !(0b1111 << offset)) | (af << offset))
+                        });
+
+                        $PXi { _mode: PhantomData }
+                    }
+
                     /// Configures the pin to serve as alternate function 4 (AF4)
                     pub fn into_af4(
                         self,
                         moder: &mut MODER,
                         afr: &mut $AFR,
                     ) -> $PXi<AF4> {
-                        let offset = 2 * $i;
-
                         // alternate function mode
                         let mode = 0b10;
-                        moder.moder().modify(|r, w| unsafe {
-                            w.bits((r.bits() & !(0b11 << offset)) | (mode << offset))
-                        });
+                        moder
+                            .moder()
+                            .modify(|r, w| unsafe { w.bits(moder_bits(r.bits(), $i, mode)) });
 
                         let af = 4;
                         let offset = 4 * ($i % 8);
@@ -245,19 +1121,40 @@ macro_rules! gpio {
                         $PXi { _mode: PhantomData }
                     }
 
+                    /// Configures the pin to serve as alternate function 4 (AF4) in open drain
+                    /// mode with a high output speed, as needed by the I2C peripherals
+                    pub fn into_af4_i2c(
+                        self,
+                        moder: &mut MODER,
+                        otyper: &mut OTYPER,
+                        ospeedr: &mut OSPEEDR,
+                        afr: &mut $AFR,
+                    ) -> $PXi<AF4> {
+                        // open drain output
+                        otyper
+                            .otyper()
+                            .modify(|r, w| unsafe { w.bits(r.bits() | (0b1 << $i)) });
+
+                        let offset = 2 * $i;
+                        let speed = Speed::High.bits();
+                        ospeedr.ospeedr().modify(|r, w| unsafe {
+                            w.bits((r.bits() & !(0b11 << offset)) | (speed << offset))
+                        });
+
+                        self.into_af4(moder, afr)
+                    }
+
                     /// Configures the pin to serve as alternate function 5 (AF5)
                     pub fn into_af5(
                         self,
                         moder: &mut MODER,
                         afr: &mut $AFR,
                     ) -> $PXi<AF5> {
-                        let offset = 2 * $i;
-
                         // alternate function mode
                         let mode = 0b10;
-                        moder.moder().modify(|r, w| unsafe {
-                            w.bits((r.bits() & !(0b11 << offset)) | (mode << offset))
-                        });
+                        moder
+                            .moder()
+                            .modify(|r, w| unsafe { w.bits(moder_bits(r.bits(), $i, mode)) });
 
                         let af = 5;
                         let offset = 4 * ($i % 8);
@@ -274,13 +1171,11 @@ macro_rules! gpio {
                         moder: &mut MODER,
                         afr: &mut $AFR,
                     ) -> $PXi<AF6> {
-                        let offset = 2 * $i;
-
                         // alternate function mode
                         let mode = 0b10;
-                        moder.moder().modify(|r, w| unsafe {
-                            w.bits((r.bits() & !(0b11 << offset)) | (mode << offset))
-                        });
+                        moder
+                            .moder()
+                            .modify(|r, w| unsafe { w.bits(moder_bits(r.bits(), $i, mode)) });
 
                         let af = 6;
                         let offset = 4 * ($i % 8);
@@ -297,13 +1192,11 @@ macro_rules! gpio {
                         moder: &mut MODER,
                         afr: &mut $AFR,
                     ) -> $PXi<AF7> {
-                        let offset = 2 * $i;
-
                         // alternate function mode
                         let mode = 0b10;
-                        moder.moder().modify(|r, w| unsafe {
-                            w.bits((r.bits() & !(0b11 << offset)) | (mode << offset))
-                        });
+                        moder
+                            .moder()
+                            .modify(|r, w| unsafe { w.bits(moder_bits(r.bits(), $i, mode)) });
 
                         let af = 7;
                         let offset = 4 * ($i % 8);
@@ -315,6 +1208,30 @@ macro_rules! gpio {
                         $PXi { _mode: PhantomData }
                     }
 
+                    /// Configures the pin to operate in analog mode, e.g. as an ADC, DAC,
+                    /// comparator or operational amplifier terminal
+                    ///
+                    /// This also disconnects the pull-up / pull-down resistors
+                    pub fn into_analog(
+                        self,
+                        moder: &mut MODER,
+                        pupdr: &mut PUPDR,
+                    ) -> $PXi<Analog> {
+                        let offset = 2 * $i;
+
+                        // no pull-up or pull-down
+                        pupdr
+                            .pupdr()
+                            .modify(|r, w| unsafe { w.bits(r.bits() & !(0b11 << offset)) });
+
+                        // analog mode
+                        moder
+                            .moder()
+                            .modify(|r, w| unsafe { w.bits(moder_bits(r.bits(), $i, 0b11)) });
+
+                        $PXi { _mode: PhantomData }
+                    }
+
                     /// Configures the pin to operate as a floating input pin
                     pub fn into_floating_input(
                         self,
@@ -326,7 +1243,7 @@ macro_rules! gpio {
                         // input mode
                         moder
                             .moder()
-                            .modify(|r, w| unsafe { w.bits(r.bits() & !(0b11 << offset)) });
+                            .modify(|r, w| unsafe { w.bits(moder_bits(r.bits(), $i, 0b00)) });
 
                         // no pull-up or pull-down
                         pupdr
@@ -347,7 +1264,7 @@ macro_rules! gpio {
                         // input mode
                         moder
                             .moder()
-                            .modify(|r, w| unsafe { w.bits(r.bits() & !(0b11 << offset)) });
+                            .modify(|r, w| unsafe { w.bits(moder_bits(r.bits(), $i, 0b00)) });
 
                         // pull-down
                         pupdr.pupdr().modify(|r, w| unsafe {
@@ -368,7 +1285,7 @@ macro_rules! gpio {
                         // input mode
                         moder
                             .moder()
-                            .modify(|r, w| unsafe { w.bits(r.bits() & !(0b11 << offset)) });
+                            .modify(|r, w| unsafe { w.bits(moder_bits(r.bits(), $i, 0b00)) });
 
                         // pull-up
                         pupdr.pupdr().modify(|r, w| unsafe {
@@ -384,13 +1301,11 @@ macro_rules! gpio {
                         moder: &mut MODER,
                         otyper: &mut OTYPER,
                     ) -> $PXi<Output<OpenDrain>> {
-                        let offset = 2 * $i;
-
                         // general purpose output mode
                         let mode = 0b01;
-                        moder.moder().modify(|r, w| unsafe {
-                            w.bits((r.bits() & !(0b11 << offset)) | (mode << offset))
-                        });
+                        moder
+                            .moder()
+                            .modify(|r, w| unsafe { w.bits(moder_bits(r.bits(), $i, mode)) });
 
                         // open drain output
                         otyper
@@ -406,13 +1321,11 @@ macro_rules! gpio {
                         moder: &mut MODER,
                         otyper: &mut OTYPER,
                     ) -> $PXi<Output<PushPull>> {
-                        let offset = 2 * $i;
-
                         // general purpose output mode
                         let mode = 0b01;
-                        moder.moder().modify(|r, w| unsafe {
-                            w.bits((r.bits() & !(0b11 << offset)) | (mode << offset))
-                        });
+                        moder
+                            .moder()
+                            .modify(|r, w| unsafe { w.bits(moder_bits(r.bits(), $i, mode)) });
 
                         // push pull output
                         otyper
@@ -423,7 +1336,124 @@ macro_rules! gpio {
                     }
                 }
 
+                impl<MODE> $PXi<Input<MODE>> {
+                    /// Erases the pin number from the type
+                    ///
+                    /// This is useful when you want to collect the pins into an array where you
+                    /// need all the elements to have the same type
+                    pub fn downgrade(self) -> $PXx<Input<MODE>> {
+                        $PXx {
+                            i: $i,
+                            _mode: self._mode,
+                        }
+                    }
+
+                    /// Turns the pin into a `PullProbe`, which can switch the pull resistor
+                    /// between reads
+                    pub fn into_pull_probe<'a>(self, pupdr: &'a mut PUPDR) -> PullProbe<'a, Self> {
+                        let offset = 2 * $i;
+                        let original = (pupdr.pupdr().read().bits() >> offset) & 0b11;
+
+                        PullProbe {
+                            pin: self,
+                            i: $i,
+                            original,
+                            pupdr,
+                        }
+                    }
+
+                    /// Configures the pin as a push pull output that drives the level currently
+                    /// read on the line
+                    ///
+                    /// The output level is set before the output driver is enabled, so taking
+                    /// over a shared line doesn't produce a glitch
+                    pub fn into_push_pull_output_matching_input(
+                        self,
+                        moder: &mut MODER,
+                        otyper: &mut OTYPER,
+                    ) -> $PXi<Output<PushPull>> {
+                        // NOTE(unsafe) atomic read with no side effects
+                        let high = unsafe { (*$GPIOX::ptr()).idr.read().bits() & (1 << $i) != 0 };
+                        let bsrr = if high { 1 << $i } else { 1 << (16 + $i) };
+                        // NOTE(unsafe) atomic write to a stateless register
+                        unsafe { (*$GPIOX::ptr()).bsrr.write(|w| w.bits(bsrr)) }
+
+                        // push pull output
+                        otyper
+                            .otyper()
+                            .modify(|r, w| unsafe { w.bits(r.bits() & !(0b1 << $i)) });
+
+                        // general purpose output mode
+                        let mode = 0b01;
+                        moder
+                            .moder()
+                            .modify(|r, w| unsafe { w.bits(moder_bits(r.bits(), $i, mode)) });
+
+                        $PXi { _mode: PhantomData }
+                    }
+                }
+
+                unsafe impl<MODE> ConfigPin for $PXi<MODE> {
+                    type FloatingInput = $PXi<Input<Floating>>;
+                    type PullDownInput = $PXi<Input<PullDown>>;
+                    type PullUpInput = $PXi<Input<PullUp>>;
+                    type OpenDrainOutput = $PXi<Output<OpenDrain>>;
+                    type PushPullOutput = $PXi<Output<PushPull>>;
+                    type Analog = $PXi<Analog>;
+
+                    fn floating_input(self, cfg: &mut Config) -> Self::FloatingInput {
+                        self.into_floating_input(&mut cfg.moder, &mut cfg.pupdr)
+                    }
+
+                    fn pull_down_input(self, cfg: &mut Config) -> Self::PullDownInput {
+                        self.into_pull_down_input(&mut cfg.moder, &mut cfg.pupdr)
+                    }
+
+                    fn pull_up_input(self, cfg: &mut Config) -> Self::PullUpInput {
+                        self.into_pull_up_input(&mut cfg.moder, &mut cfg.pupdr)
+                    }
+
+                    fn open_drain_output(self, cfg: &mut Config) -> Self::OpenDrainOutput {
+                        self.into_open_drain_output(&mut cfg.moder, &mut cfg.otyper)
+                    }
+
+                    fn push_pull_output(self, cfg: &mut Config) -> Self::PushPullOutput {
+                        self.into_push_pull_output(&mut cfg.moder, &mut cfg.otyper)
+                    }
+
+                    fn analog(self, cfg: &mut Config) -> Self::Analog {
+                        self.into_analog(&mut cfg.moder, &mut cfg.pupdr)
+                    }
+
+                    fn set_speed(&mut self, cfg: &mut Config, speed: Speed) {
+                        $PXi::set_speed(self, &mut cfg.ospeedr, speed)
+                    }
+                }
+
+                unsafe impl<MODE, AF> GroupPin<AF> for $PXi<MODE> {
+                    const I: u8 = $i;
+
+                    type Output = $PXi<AF>;
+
+                    unsafe fn into_group_mode(self) -> $PXi<AF> {
+                        $PXi { _mode: PhantomData }
+                    }
+                }
+
                 impl $PXi<Output<OpenDrain>> {
+                    /// Returns `true` if the line is actually high, i.e. the pin is released and
+                    /// nothing else is pulling the line low
+                    pub fn input_is_high(&self) -> bool {
+                        !self.input_is_low()
+                    }
+
+                    /// Returns `true` if the line is actually low, either because this pin or
+                    /// something else is driving it low
+                    pub fn input_is_low(&self) -> bool {
+                        // NOTE(unsafe) atomic read with no side effects
+                        unsafe { (*$GPIOX::ptr()).idr.read().bits() & (1 << $i) == 0 }
+                    }
+
                     /// Enables / disables the internal pull up
                     pub fn internal_pull_up(&mut self, pupdr: &mut PUPDR, on: bool) {
                         let offset = 2 * $i;
@@ -441,6 +1471,26 @@ macro_rules! gpio {
                 }
 
                 impl<MODE> $PXi<Output<MODE>> {
+                    /// Returns the address of the port BSRR register and the bit mask of this pin
+                    ///
+                    /// For hot loops where even a method call is too slow: writing `mask` to the
+                    /// address drives the pin high and writing `mask << 16` drives it low.
+                    ///
+                    /// # Safety
+                    ///
+                    /// Only ever write `mask` or `mask << 16` (or the masks of other pins owned
+                    /// by the caller) to the address, and only while the pin is still in output
+                    /// mode. The writes are atomic so they don't need a critical section and
+                    /// don't race with other pins of the port.
+                    pub unsafe fn port_ptr_and_mask(&self) -> (*mut u32, u32) {
+                        bsrr_ptr_and_mask($i)
+                    }
+
+                    /// Inverts the logic of the pin, see `ActiveLow`
+                    pub fn into_active_low(self) -> ActiveLow<Self> {
+                        ActiveLow::new(self)
+                    }
+
                     /// Erases the pin number from the type
                     ///
                     /// This is useful when you want to collect the pins into an array where you
@@ -453,6 +1503,42 @@ macro_rules! gpio {
                     }
                 }
 
+                impl<MODE> InputPin for $PXi<Input<MODE>> {
+                    fn is_high(&self) -> bool {
+                        !self.is_low()
+                    }
+
+                    fn is_low(&self) -> bool {
+                        // NOTE(unsafe) atomic read with no side effects
+                        unsafe { (*$GPIOX::ptr()).idr.read().bits() & (1 << $i) == 0 }
+                    }
+                }
+
+                /// Reads the level of the line, which, unlike the output data register, reflects
+                /// whether another device is pulling it low
+                impl InputPin for $PXi<Output<OpenDrain>> {
+                    fn is_high(&self) -> bool {
+                        self.input_is_high()
+                    }
+
+                    fn is_low(&self) -> bool {
+                        self.input_is_low()
+                    }
+                }
+
+                impl<MODE> StatefulOutputPin for $PXi<Output<MODE>> {
+                    fn is_set_high(&self) -> bool {
+                        !self.is_set_low()
+                    }
+
+                    fn is_set_low(&self) -> bool {
+                        // NOTE(unsafe) atomic read with no side effects
+                        unsafe { (*$GPIOX::ptr()).odr.read().bits() & (1 << $i) == 0 }
+                    }
+                }
+
+                impl<MODE> toggleable::Default for $PXi<Output<MODE>> {}
+
                 impl<MODE> OutputPin for $PXi<Output<MODE>> {
                     fn set_high(&mut self) {
                         // NOTE(unsafe) atomic write to a stateless register
@@ -469,7 +1555,7 @@ macro_rules! gpio {
     }
 }
 
-gpio!(GPIOA, gpioa, gpioa, iopaen, ioparst, PAx, [
+gpio!(GPIOA, gpioa, gpioa, iopaen, ioparst, PAx, A, [
     PA0: (pa0, 0, Input<Floating>, AFRL),
     PA1: (pa1, 1, Input<Floating>, AFRL),
     PA2: (pa2, 2, Input<Floating>, AFRL),
@@ -483,19 +1569,29 @@ gpio!(GPIOA, gpioa, gpioa, iopaen, ioparst, PAx, [
     PA10: (pa10, 10, Input<Floating>, AFRH),
     PA11: (pa11, 11, Input<Floating>, AFRH),
     PA12: (pa12, 12, Input<Floating>, AFRH),
-    // TODO these are configured as JTAG pins
-    // PA13: (13, Input<Floating>),
-    // PA14: (14, Input<Floating>),
-    PA15: (pa15, 15, Input<Floating>, AFRH),
+    // NOTE PA13 - PA15 are JTAG / SWD pins so they start in AF0
+    ///
+    /// SWDIO: changing its mode cuts off an attached SWD debugger
+    PA13: (pa13, 13, AF0, AFRH),
+    ///
+    /// SWCLK: changing its mode cuts off an attached SWD debugger
+    PA14: (pa14, 14, AF0, AFRH),
+    ///
+    /// JTDI: changing its mode breaks JTAG, SWD keeps working
+    PA15: (pa15, 15, AF0, AFRH),
 ]);
 
-gpio!(GPIOB, gpiob, gpiob, iopben, iopbrst, PBx, [
+gpio!(GPIOB, gpiob, gpiob, iopben, iopbrst, PBx, B, [
     PB0: (pb0, 0, Input<Floating>, AFRL),
     PB1: (pb1, 1, Input<Floating>, AFRL),
     PB2: (pb2, 2, Input<Floating>, AFRL),
-    // TODO these are configured as JTAG pins
-    PB3: (pb3, 3, Input<Floating>, AFRL),
-    PB4: (pb4, 4, Input<Floating>, AFRL),
+    // NOTE PB3 and PB4 are JTAG pins so they start in AF0
+    ///
+    /// JTDO / SWO: changing its mode breaks JTAG and the SWO trace output, SWD keeps working
+    PB3: (pb3, 3, AF0, AFRL),
+    ///
+    /// NJTRST: changing its mode breaks JTAG, SWD keeps working
+    PB4: (pb4, 4, AF0, AFRL),
     PB5: (pb5, 5, Input<Floating>, AFRL),
     PB6: (pb6, 6, Input<Floating>, AFRL),
     PB7: (pb7, 7, Input<Floating>, AFRL),
@@ -509,7 +1605,7 @@ gpio!(GPIOB, gpiob, gpiob, iopben, iopbrst, PBx, [
     PB15: (pb15, 15, Input<Floating>, AFRH),
 ]);
 
-gpio!(GPIOC, gpioc, gpioc, iopcen, iopcrst, PCx, [
+gpio!(GPIOC, gpioc, gpioc, iopcen, iopcrst, PCx, C, [
     PC0: (pc0, 0, Input<Floating>, AFRL),
     PC1: (pc1, 1, Input<Floating>, AFRL),
     PC2: (pc2, 2, Input<Floating>, AFRL),
@@ -528,7 +1624,7 @@ gpio!(GPIOC, gpioc, gpioc, iopcen, iopcrst, PCx, [
     PC15: (pc15, 15, Input<Floating>, AFRH),
 ]);
 
-gpio!(GPIOD, gpiod, gpioc, iopden, iopdrst, PDx, [
+gpio!(GPIOD, gpiod, gpioc, iopden, iopdrst, PDx, D, [
     PD0: (pd0, 0, Input<Floating>, AFRL),
     PD1: (pd1, 1, Input<Floating>, AFRL),
     PD2: (pd2, 2, Input<Floating>, AFRL),
@@ -547,7 +1643,7 @@ gpio!(GPIOD, gpiod, gpioc, iopden, iopdrst, PDx, [
     PD15: (pd15, 15, Input<Floating>, AFRH),
 ]);
 
-gpio!(GPIOE, gpioe, gpioc, iopeen, ioperst, PEx, [
+gpio!(GPIOE, gpioe, gpioc, iopeen, ioperst, PEx, E, [
     PE0: (pe0, 0, Input<Floating>, AFRL),
     PE1: (pe1, 1, Input<Floating>, AFRL),
     PE2: (pe2, 2, Input<Floating>, AFRL),
@@ -566,7 +1662,7 @@ gpio!(GPIOE, gpioe, gpioc, iopeen, ioperst, PEx, [
     PE15: (pe15, 15, Input<Floating>, AFRH),
 ]);
 
-gpio!(GPIOF, gpiof, gpioc, iopfen, iopfrst, PFx, [
+gpio!(GPIOF, gpiof, gpioc, iopfen, iopfrst, PFx, F, [
     PF0: (pf0, 0, Input<Floating>, AFRL),
     PF1: (pf1, 1, Input<Floating>, AFRL),
     PF2: (pf2, 2, Input<Floating>, AFRL),
@@ -575,3 +1671,71 @@ gpio!(GPIOF, gpiof, gpioc, iopfen, iopfrst, PFx, [
     PF9: (pf9, 9, Input<Floating>, AFRH),
     PF10: (pf10, 10, Input<Floating>, AFRH),
 ]);
+
+#[cfg(test)]
+mod tests {
+    use super::{alternate_function, moder_bits};
+    use super::gpioa::{PA0, PA1, PA2, PUPDR};
+    use super::{Floating, Input, Output, PushPull};
+    use hal::digital::{StatefulOutputPin, ToggleableOutputPin};
+
+    #[test]
+    fn alternate_function_readback() {
+        // PX1 in AF7, PX9 in AF12, the other pins in input mode
+        let moder = 0b10 << 2 | 0b10 << 18;
+        let afrl = 7 << 4;
+        let afrh = 12 << 4;
+
+        assert_eq!(alternate_function(moder, afrl, afrh, 1), Some(7));
+        assert_eq!(alternate_function(moder, afrl, afrh, 9), Some(12));
+        assert_eq!(alternate_function(moder, afrl, afrh, 0), None);
+
+        // output mode
+        assert_eq!(alternate_function(0b01 << 2, afrl, afrh, 1), None);
+    }
+
+    #[test]
+    fn moder_patterns() {
+        // GPIOA reset value: PA13 / PA14 / PA15 in alternate function mode, the rest in input mode
+        let moder = 0xa800_0000;
+
+        // PA0 and PA4 to analog mode
+        let moder = moder_bits(moder, 0, 0b11);
+        let moder = moder_bits(moder, 4, 0b11);
+        assert_eq!(moder, 0xa800_0303);
+
+        // PA15 from alternate function to output mode
+        let moder = moder_bits(moder, 15, 0b01);
+        assert_eq!(moder, 0x6800_0303);
+
+        // PA4 from analog to input mode and PA5 to alternate function mode
+        let moder = moder_bits(moder, 4, 0b00);
+        let moder = moder_bits(moder, 5, 0b10);
+        assert_eq!(moder, 0x6800_0803);
+    }
+
+    // NOTE this one only needs to compile: running it would touch the real GPIOA registers
+    #[allow(dead_code)]
+    fn pull_probe(pin: PA0<Input<Floating>>, pupdr: &mut PUPDR) -> (bool, PA0<Input<Floating>>) {
+        let mut probe = pin.into_pull_probe(pupdr);
+        let high = probe.with_pull_up().read();
+
+        (high, probe.free())
+    }
+
+    // NOTE this one only needs to compile: running it would touch the real GPIOA registers
+    #[allow(dead_code)]
+    fn toggle_downgraded(
+        pa0: PA0<Output<PushPull>>,
+        pa1: PA1<Output<PushPull>>,
+        pa2: PA2<Output<PushPull>>,
+    ) -> [bool; 3] {
+        let mut leds = [pa0.downgrade(), pa1.downgrade(), pa2.downgrade()];
+
+        for led in leds.iter_mut() {
+            led.toggle();
+        }
+
+        [leds[0].is_set_high(), leds[1].is_set_high(), leds[2].is_set_high()]
+    }
+}
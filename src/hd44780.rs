@@ -0,0 +1,283 @@
+//! HD44780 character LCD driven over GPIO in 4-bit mode
+//!
+//! Needs the RS and E pins plus the upper four data lines (D4 - D7); R/W must be tied to ground
+//! as the busy flag is never read, the command execution times are waited out instead. The data
+//! pins are taken as an array so they can be any four pins of any ports, e.g. downgraded `PXx`
+//! pins.
+
+use hal::blocking::delay::DelayUs;
+use hal::digital::OutputPin;
+
+/// Clear display command
+const CLEAR: u8 = 0x01;
+/// Entry mode set command: increment the address, don't shift the display
+const ENTRY_MODE: u8 = 0x06;
+/// Display control command: display on, cursor off, blinking off
+const DISPLAY_ON: u8 = 0x0c;
+/// Function set command: 4-bit interface, 2 lines, 5x8 dots
+const FUNCTION_SET: u8 = 0x28;
+/// Set DDRAM address command
+const SET_DDRAM_ADDRESS: u8 = 0x80;
+
+/// DDRAM address of the first character of each line
+const LINES: [u8; 4] = [0x00, 0x40, 0x14, 0x54];
+
+/// HD44780 LCD
+pub struct Hd44780<RS, EN, DATA, D> {
+    rs: RS,
+    en: EN,
+    data: [DATA; 4],
+    delay: D,
+}
+
+impl<RS, EN, DATA, D> Hd44780<RS, EN, DATA, D>
+where
+    RS: OutputPin,
+    EN: OutputPin,
+    DATA: OutputPin,
+    D: DelayUs<u16>,
+{
+    /// Drives an LCD through the `rs` and `en` pins and the `data` pins, D4 first
+    ///
+    /// Call `init` before anything else
+    pub fn new(mut rs: RS, mut en: EN, data: [DATA; 4], delay: D) -> Self {
+        rs.set_low();
+        en.set_low();
+
+        Hd44780 { rs, en, data, delay }
+    }
+
+    /// Initializes the LCD (4-bit interface, 2 lines, display on, cursor off) and clears it
+    ///
+    /// This waits for the LCD to power up first, which takes up to 50 ms
+    pub fn init(&mut self) {
+        self.delay.delay_us(50_000);
+
+        // the LCD may be in 8-bit mode or half way through a 4-bit transfer; this sequence puts it
+        // back in 8-bit mode whatever its state, and then switches it to 4-bit mode
+        self.rs.set_low();
+        self.write_nibble(0x3);
+        self.delay.delay_us(4_100);
+        self.write_nibble(0x3);
+        self.delay.delay_us(100);
+        self.write_nibble(0x3);
+        self.write_nibble(0x2);
+
+        self.command(FUNCTION_SET);
+        self.command(DISPLAY_ON);
+        self.clear();
+        self.command(ENTRY_MODE);
+    }
+
+    /// Clears the display and moves the cursor to the top left corner
+    pub fn clear(&mut self) {
+        self.command(CLEAR);
+        // this command is much slower than the others
+        self.delay.delay_us(2_000);
+    }
+
+    /// Moves the cursor to column `col` of line `row`, both starting at 0
+    ///
+    /// `col` must be below the line length of the DDRAM: 40 characters on the 1 and 2 line
+    /// displays, 20 on the 4 line ones. `row` is taken modulo 4. Out of range columns don't panic
+    /// but wrap around the 7-bit DDRAM address space, so the cursor lands on some other line.
+    pub fn set_cursor(&mut self, col: u8, row: u8) {
+        let address = LINES[usize::from(row % 4)].wrapping_add(col) & 0x7f;
+
+        self.command(SET_DDRAM_ADDRESS | address);
+    }
+
+    /// Writes `s` at the cursor position
+    ///
+    /// The LCD character set matches ASCII for the printable characters, except for `\` and `~`
+    pub fn write_str(&mut self, s: &str) {
+        for byte in s.bytes() {
+            self.write_data(byte);
+        }
+    }
+
+    /// Writes a single character, as a code of the LCD character set, at the cursor position
+    pub fn write_data(&mut self, byte: u8) {
+        self.rs.set_high();
+        self.write_byte(byte);
+    }
+
+    /// Sends a raw command byte
+    pub fn command(&mut self, byte: u8) {
+        self.rs.set_low();
+        self.write_byte(byte);
+    }
+
+    /// Releases the pins and the delay source
+    pub fn free(self) -> (RS, EN, [DATA; 4], D) {
+        (self.rs, self.en, self.data, self.delay)
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        self.write_nibble(byte >> 4);
+        self.write_nibble(byte & 0xf);
+    }
+
+    fn write_nibble(&mut self, nibble: u8) {
+        for (i, pin) in self.data.iter_mut().enumerate() {
+            if nibble & (1 << i) != 0 {
+                pin.set_high();
+            } else {
+                pin.set_low();
+            }
+        }
+
+        // the data is latched on the falling edge of E
+        self.en.set_high();
+        self.delay.delay_us(1);
+        self.en.set_low();
+
+        // most commands take 37 us to execute
+        self.delay.delay_us(50);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::{Cell, RefCell};
+
+    use hal::blocking::delay::DelayUs;
+    use hal::digital::OutputPin;
+
+    use super::Hd44780;
+
+    /// Delay that returns right away
+    struct Delay;
+
+    impl DelayUs<u16> for Delay {
+        fn delay_us(&mut self, _: u16) {}
+    }
+
+    /// State of the LCD bus, plus the (RS, D7 - D4) values latched on every falling edge of E
+    struct Bus {
+        rs: Cell<bool>,
+        en: Cell<bool>,
+        data: Cell<u8>,
+        latched: RefCell<([(bool, u8); 32], usize)>,
+    }
+
+    impl Bus {
+        fn new() -> Self {
+            Bus {
+                rs: Cell::new(false),
+                en: Cell::new(false),
+                data: Cell::new(0),
+                latched: RefCell::new(([(false, 0); 32], 0)),
+            }
+        }
+
+        fn latched(&self) -> ([(bool, u8); 32], usize) {
+            *self.latched.borrow()
+        }
+    }
+
+    enum Line {
+        Rs,
+        En,
+        D(u8),
+    }
+
+    struct Pin<'a> {
+        bus: &'a Bus,
+        line: Line,
+    }
+
+    impl<'a> Pin<'a> {
+        fn set(&mut self, high: bool) {
+            match self.line {
+                Line::Rs => self.bus.rs.set(high),
+                Line::En => {
+                    if self.bus.en.get() && !high {
+                        let mut latched = self.bus.latched.borrow_mut();
+                        let n = latched.1;
+                        latched.0[n] = (self.bus.rs.get(), self.bus.data.get());
+                        latched.1 += 1;
+                    }
+                    self.bus.en.set(high);
+                }
+                Line::D(i) => {
+                    let data = self.bus.data.get() & !(1 << i);
+                    self.bus.data.set(data | (u8::from(high) << i));
+                }
+            }
+        }
+    }
+
+    impl<'a> OutputPin for Pin<'a> {
+        fn set_high(&mut self) {
+            self.set(true);
+        }
+
+        fn set_low(&mut self) {
+            self.set(false);
+        }
+    }
+
+    fn lcd(bus: &Bus) -> Hd44780<Pin, Pin, Pin, Delay> {
+        let pin = |line| Pin { bus, line };
+        let data = [pin(Line::D(0)), pin(Line::D(1)), pin(Line::D(2)), pin(Line::D(3))];
+
+        Hd44780::new(pin(Line::Rs), pin(Line::En), data, Delay)
+    }
+
+    #[test]
+    fn init_sequence() {
+        let bus = Bus::new();
+        lcd(&bus).init();
+
+        let (latched, n) = bus.latched();
+        let c = false;
+        assert_eq!(
+            latched[..n],
+            [
+                // back to 8-bit mode, then to 4-bit mode
+                (c, 0x3),
+                (c, 0x3),
+                (c, 0x3),
+                (c, 0x2),
+                // function set
+                (c, 0x2),
+                (c, 0x8),
+                // display on
+                (c, 0x0),
+                (c, 0xc),
+                // clear
+                (c, 0x0),
+                (c, 0x1),
+                // entry mode
+                (c, 0x0),
+                (c, 0x6),
+            ]
+        );
+    }
+
+    #[test]
+    fn high_nibble_first() {
+        let bus = Bus::new();
+        let mut lcd = lcd(&bus);
+        lcd.write_str("A~");
+        lcd.set_cursor(3, 1);
+
+        let (latched, n) = bus.latched();
+        let (c, d) = (false, true);
+        assert_eq!(
+            latched[..n],
+            [(d, 0x4), (d, 0x1), (d, 0x7), (d, 0xe), (c, 0xc), (c, 0x3)]
+        );
+    }
+
+    #[test]
+    fn cursor_wraps() {
+        let bus = Bus::new();
+        lcd(&bus).set_cursor(255, 3);
+
+        // 0x54 + 255 wraps to 0x53
+        let (latched, n) = bus.latched();
+        assert_eq!(latched[..n], [(false, 0xd), (false, 0x3)]);
+    }
+}
@@ -1,15 +1,18 @@
 //! Inter-Integrated Circuit (I2C) bus
 
 use cast::u8;
-use stm32f30x::{I2C1, I2C2};
+use stm32f30x::{Interrupt, GPIOA, GPIOB, GPIOF, I2C1, I2C2, RCC};
 
 use gpio::gpioa::{PA10, PA9};
 use gpio::gpiob::{PB6, PB7, PB8, PB9};
 use gpio::gpiof::{PF0, PF1, PF6};
-use gpio::AF4;
+use gpio::{gpioa, gpiob, gpiof, AF4};
+use hal::blocking::delay::DelayUs;
 use hal::blocking::i2c::{Write, WriteRead};
-use rcc::{APB1, Clocks};
-use time::Hertz;
+use hal::digital::{InputPin, OutputPin};
+use rcc::{APB1, Clocks, HSI};
+use syscfg::{FastModePlus, CFGR1};
+use time::{Hertz, U32Ext};
 
 /// I2C error
 #[derive(Debug)]
@@ -26,6 +29,40 @@ pub enum Error {
     _Extensible,
 }
 
+flags! {
+    /// I2C status flags (ISR register)
+    pub struct Flags {
+        /// The data register is empty
+        const TXE = 0;
+        /// New data can be sent
+        const TXIS = 1;
+        /// A received byte can be read
+        const RXNE = 2;
+        /// A NACK was received
+        const NACKF = 4;
+        /// A STOP condition was detected
+        const STOPF = 5;
+        /// Transfer complete
+        const TC = 6;
+        /// Transfer complete, waiting for the next chunk (reload)
+        const TCR = 7;
+        /// Bus error
+        const BERR = 8;
+        /// Arbitration lost
+        const ARLO = 9;
+        /// A transfer is ongoing on the bus
+        const BUSY = 15;
+    }
+}
+
+impl Flags {
+    /// Flags that are cleared through the ICR register; the others are cleared by reading or
+    /// writing data, or by the hardware
+    fn clearable() -> Self {
+        Flags::NACKF | Flags::STOPF | Flags::BERR | Flags::ARLO
+    }
+}
+
 // FIXME these should be "closed" traits
 /// SCL pin -- DO NOT IMPLEMENT THIS TRAIT
 pub unsafe trait SclPin<I2C> {}
@@ -54,6 +91,123 @@ pub struct I2c<I2C, PINS> {
     pins: PINS,
 }
 
+/// SCL / SDA pin pair that `recover` can take over -- DO NOT IMPLEMENT THIS TRAIT
+///
+/// Implemented for the pairs whose pins belong to the same GPIO port
+pub unsafe trait RecoverPins: Sized {
+    /// Configuration registers of the port the pins belong to (see `Parts::configure`)
+    type Config;
+
+    #[doc(hidden)]
+    fn recover<D>(self, cfg: &mut Self::Config, delay: &mut D) -> (Self, Result<(), Error>)
+    where
+        D: DelayUs<u8>;
+}
+
+macro_rules! recover_pins {
+    ($($GPIOX:ident, $gpiox:ident: ($SCL:ident: $scl:expr, $SDA:ident: $sda:expr),)+) => {
+        $(
+            unsafe impl RecoverPins for ($SCL<AF4>, $SDA<AF4>) {
+                type Config = $gpiox::Config;
+
+                fn recover<D>(
+                    self,
+                    cfg: &mut $gpiox::Config,
+                    delay: &mut D,
+                ) -> (Self, Result<(), Error>)
+                where
+                    D: DelayUs<u8>,
+                {
+                    // release both lines *before* switching to output mode so they are not pulled
+                    // low, which could look like a START or clock a slave, while ODR is still 0
+                    // NOTE(unsafe) atomic write to a stateless register
+                    unsafe { (*$GPIOX::ptr()).bsrr.write(|w| w.bits(1 << $scl | 1 << $sda)) }
+
+                    let (scl, sda) = self;
+                    let mut scl = cfg.open_drain_output(scl);
+                    let mut sda = cfg.open_drain_output(sda);
+
+                    let result = clock_out(&mut scl, &mut sda, delay);
+
+                    // the pins stay open drain, as the I2C peripheral needs them
+                    (cfg.alternate::<_, AF4>((scl, sda)), result)
+                }
+            }
+        )+
+    }
+}
+
+recover_pins! {
+    GPIOA, gpioa: (PA9: 9, PA10: 10),
+    GPIOB, gpiob: (PB6: 6, PB7: 7),
+    GPIOB, gpiob: (PB6: 6, PB9: 9),
+    GPIOB, gpiob: (PB8: 8, PB7: 7),
+    GPIOB, gpiob: (PB8: 8, PB9: 9),
+    GPIOF, gpiof: (PF1: 1, PF0: 0),
+    GPIOF, gpiof: (PF6: 6, PF0: 0),
+}
+
+/// Frees a bus that's stuck because a slave is holding SDA low (e.g. after the master was
+/// reset in the middle of a transfer)
+///
+/// `pins` are the (SCL, SDA) pins, in the AF4 mode, of a bus that's not driven by an `I2c`
+/// (e.g. after `I2c::free`), and `cfg` the configuration registers of their port. The pins are
+/// temporarily switched to open drain outputs; SCL is clocked up to 9 times, at roughly 100 KHz,
+/// until the slave releases SDA and then a STOP condition is issued. The pins are then put back
+/// into the AF4 mode, as open drain, and handed back, ready for the `I2c` constructor.
+///
+/// The pins are returned along with `Err(Error::Bus)` if SDA is still held low after 9 clock
+/// pulses
+pub fn recover<PINS, D>(
+    pins: PINS,
+    cfg: &mut PINS::Config,
+    delay: &mut D,
+) -> (PINS, Result<(), Error>)
+where
+    PINS: RecoverPins,
+    D: DelayUs<u8>,
+{
+    pins.recover(cfg, delay)
+}
+
+fn clock_out<SCL, SDA, D>(scl: &mut SCL, sda: &mut SDA, delay: &mut D) -> Result<(), Error>
+where
+    SCL: OutputPin,
+    SDA: InputPin + OutputPin,
+    D: DelayUs<u8>,
+{
+    // release both lines
+    sda.set_high();
+    scl.set_high();
+    delay.delay_us(5);
+
+    let mut pulses = 0;
+    while sda.is_low() {
+        if pulses == 9 {
+            return Err(Error::Bus);
+        }
+
+        scl.set_low();
+        delay.delay_us(5);
+        scl.set_high();
+        delay.delay_us(5);
+
+        pulses += 1;
+    }
+
+    // STOP: SDA goes from low to high while SCL is high
+    scl.set_low();
+    delay.delay_us(5);
+    sda.set_low();
+    delay.delay_us(5);
+    scl.set_high();
+    delay.delay_us(5);
+    sda.set_high();
+    delay.delay_us(5);
+
+    Ok(())
+}
+
 macro_rules! busy_wait {
     ($i2c:expr, $flag:ident) => {
         loop {
@@ -72,11 +226,101 @@ macro_rules! busy_wait {
     };
 }
 
+/// TIMINGR register fields
+#[derive(Debug, PartialEq)]
+struct Timing {
+    presc: u8,
+    scll: u8,
+    sclh: u8,
+    sdadel: u8,
+    scldel: u8,
+}
+
+/// Computes the TIMINGR fields that run a bus clocked at `i2cclk` at `freq`
+///
+/// The smallest prescaler that fits every field in its register is picked, to keep most of the
+/// resolution. Returns `None` if `i2cclk` is too slow, or too fast, for `freq`.
+fn timing(i2cclk: u32, freq: u32) -> Option<Timing> {
+    // TODO review compliance with the timing requirements of I2C
+    // t_I2CCLK = 1 / I2CCLK
+    // t_PRESC  = (PRESC + 1) * t_I2CCLK
+    // t_SCLL   = (SCLL + 1) * t_PRESC
+    // t_SCLH   = (SCLH + 1) * t_PRESC
+    //
+    // t_SYNC1 + t_SYNC2 > 4 * t_I2CCLK
+    // t_SCL ~= t_SYNC1 + t_SYNC2 + t_SCLL + t_SCLH
+    let ratio = (i2cclk / freq).checked_sub(4)?;
+
+    for presc in 0..16 {
+        let (scll, sclh, sdadel, scldel) = if freq >= 100_000 {
+            // fast-mode or fast-mode plus
+            // here we pick SCLL + 1 = 2 * (SCLH + 1)
+            let sclh = (ratio / (presc + 1)).checked_sub(3)? / 3;
+            let scll = 2 * (sclh + 1) - 1;
+
+            let (sdadel, scldel) = if freq > 400_000 {
+                // fast-mode plus
+                let sdadel = 0;
+                let scldel = (i2cclk / 4_000_000 / (presc + 1)).saturating_sub(1);
+
+                (sdadel, scldel)
+            } else {
+                // fast-mode
+                let sdadel = i2cclk / 8_000_000 / (presc + 1);
+                let scldel = (i2cclk / 2_000_000 / (presc + 1)).saturating_sub(1);
+
+                (sdadel, scldel)
+            };
+
+            (scll, sclh, sdadel, scldel)
+        } else {
+            // standard-mode
+            // here we pick SCLL = SCLH
+            let sclh = (ratio / (presc + 1)).checked_sub(2)? / 2;
+            let scll = sclh;
+
+            let sdadel = i2cclk / 2_000_000 / (presc + 1);
+            let scldel = (i2cclk / 800_000 / (presc + 1)).saturating_sub(1);
+
+            (scll, sclh, sdadel, scldel)
+        };
+
+        if scll <= 0xff && sclh <= 0xff && sdadel < 16 && scldel < 16 {
+            return Some(Timing {
+                presc: u8(presc).unwrap(),
+                scll: u8(scll).unwrap(),
+                sclh: u8(sclh).unwrap(),
+                sdadel: u8(sdadel).unwrap(),
+                scldel: u8(scldel).unwrap(),
+            });
+        }
+    }
+
+    None
+}
+
 macro_rules! hal {
-    ($($I2CX:ident: ($i2cX:ident, $i2cXen:ident, $i2cXrst:ident),)+) => {
+    ($($I2CX:ident: (
+        $i2cX:ident,
+        $i2cX_fast_mode_plus:ident,
+        $i2cXen:ident,
+        $i2cXrst:ident,
+        $i2cXsw:ident,
+        $FMP:ident,
+        $EV:ident,
+        $ER:ident
+    ),)+) => {
         $(
             impl<SCL, SDA> I2c<$I2CX, (SCL, SDA)> {
                 /// Configures the I2C peripheral to work in master mode
+                ///
+                /// The bus timings are derived from the I2C clock selected with
+                /// `CFGR::i2c1_clock` / `i2c2_clock`: the HSI (8 MHz, the default) or SYSCLK; not
+                /// from PCLK1.
+                ///
+                /// # Panics
+                ///
+                /// If `freq` is above 1 MHz, or the I2C clock can't produce it
                 pub fn $i2cX<F>(
                     i2c: $I2CX,
                     pins: (SCL, SDA),
@@ -96,61 +340,9 @@ macro_rules! hal {
 
                     assert!(freq <= 1_000_000);
 
-                    // TODO review compliance with the timing requirements of I2C
-                    // t_I2CCLK = 1 / PCLK1
-                    // t_PRESC  = (PRESC + 1) * t_I2CCLK
-                    // t_SCLL   = (SCLL + 1) * t_PRESC
-                    // t_SCLH   = (SCLH + 1) * t_PRESC
-                    //
-                    // t_SYNC1 + t_SYNC2 > 4 * t_I2CCLK
-                    // t_SCL ~= t_SYNC1 + t_SYNC2 + t_SCLL + t_SCLH
-                    let i2cclk = clocks.pclk1().0;
-                    let ratio = i2cclk / freq - 4;
-                    let (presc, scll, sclh, sdadel, scldel) = if freq >= 100_000 {
-                        // fast-mode or fast-mode plus
-                        // here we pick SCLL + 1 = 2 * (SCLH + 1)
-                        let presc = ratio / 387;
-
-                        let sclh = ((ratio / (presc + 1)) - 3) / 3;
-                        let scll = 2 * (sclh + 1) - 1;
-
-                        let (sdadel, scldel) = if freq > 400_000 {
-                            // fast-mode plus
-                            let sdadel = 0;
-                            let scldel = i2cclk / 4_000_000 / (presc + 1) - 1;
-
-                            (sdadel, scldel)
-                        } else {
-                            // fast-mode
-                            let sdadel = i2cclk / 8_000_000 / (presc + 1);
-                            let scldel = i2cclk / 2_000_000 / (presc + 1) - 1;
-
-                            (sdadel, scldel)
-                        };
-
-                        (presc, scll, sclh, sdadel, scldel)
-                    } else {
-                        // standard-mode
-                        // here we pick SCLL = SCLH
-                        let presc = ratio / 514;
-
-                        let sclh = ((ratio / (presc + 1)) - 2) / 2;
-                        let scll = sclh;
-
-                        let sdadel = i2cclk / 2_000_000 / (presc + 1);
-                        let scldel = i2cclk / 800_000 / (presc + 1) - 1;
-
-                        (presc, scll, sclh, sdadel, scldel)
-                    };
-
-                    let presc = u8(presc).unwrap();
-                    assert!(presc < 16);
-                    let scldel = u8(scldel).unwrap();
-                    assert!(scldel < 16);
-                    let sdadel = u8(sdadel).unwrap();
-                    assert!(sdadel < 16);
-                    let sclh = u8(sclh).unwrap();
-                    let scll = u8(scll).unwrap();
+                    let i2cclk = Self::i2cclk(&clocks).0;
+                    let Timing { presc, scll, sclh, sdadel, scldel } = timing(i2cclk, freq)
+                        .expect("the I2C clock can't produce this bus frequency");
 
                     // Configure for "fast mode" (400 KHz)
                     i2c.timingr.write(|w| unsafe {
@@ -172,6 +364,92 @@ macro_rules! hal {
                     I2c { i2c, pins }
                 }
 
+                /// Configures the I2C peripheral to work in master mode at 1 MHz (Fast-mode Plus)
+                ///
+                /// This also enables the 20 mA Fast-mode Plus drive of the pads that are
+                /// configured as I2C SCL / SDA pins so they can meet the rise time requirements.
+                ///
+                /// # Panics
+                ///
+                /// If the I2C clock is below 16 MHz, which is too slow to produce the 1 MHz
+                /// timings. The I2C clock defaults to the 8 MHz HSI; select SYSCLK with
+                /// `CFGR::i2c1_clock` / `i2c2_clock`.
+                pub fn $i2cX_fast_mode_plus(
+                    i2c: $I2CX,
+                    pins: (SCL, SDA),
+                    clocks: Clocks,
+                    apb1: &mut APB1,
+                    cfgr1: &mut CFGR1,
+                ) -> Self where
+                    SCL: SclPin<$I2CX>,
+                    SDA: SdaPin<$I2CX>,
+                {
+                    assert!(
+                        Self::i2cclk(&clocks).0 >= 16_000_000,
+                        "the I2C clock must be at least 16 MHz for Fast-mode Plus"
+                    );
+
+                    cfgr1.set_fast_mode_plus(FastModePlus::$FMP, true);
+
+                    Self::$i2cX(i2c, pins, 1.mhz(), clocks, apb1)
+                }
+
+                /// Returns the frequency of the I2C kernel clock (I2CCLK)
+                fn i2cclk(clocks: &Clocks) -> Hertz {
+                    // NOTE(unsafe) atomic read with no side effects
+                    if unsafe { (*RCC::ptr()).cfgr3.read().$i2cXsw().bit_is_set() } {
+                        clocks.sysclk()
+                    } else {
+                        Hertz(HSI)
+                    }
+                }
+
+                /// Configures the noise filters of the SCL and SDA inputs
+                ///
+                /// `analog` enables the analog filter, which suppresses spikes shorter than 50 ns.
+                /// `digital` is the length, in I2CCLK periods, of the digital filter that
+                /// suppresses spikes shorter than that (0 disables it; the maximum is 15).
+                ///
+                /// Out of reset the analog filter is enabled and the digital filter is disabled.
+                /// The delays the filters introduce eat into the SCL low / high periods, which the
+                /// timings computed by the constructor don't account for, so long digital filters
+                /// lower the maximum achievable bus speed.
+                pub fn set_filters(&mut self, analog: bool, digital: u8) {
+                    assert!(digital < 16);
+
+                    // the filters can only be configured while the peripheral is disabled
+                    self.i2c.cr1.modify(|_, w| w.pe().clear_bit());
+                    self.i2c.cr1.modify(|_, w| unsafe {
+                        w.anfoff().bit(!analog).dnf().bits(digital)
+                    });
+                    self.i2c.cr1.modify(|_, w| w.pe().set_bit());
+                }
+
+                /// Returns the status flags
+                pub fn flags(&self) -> Flags {
+                    Flags::from_bits_truncate(self.i2c.isr.read().bits())
+                }
+
+                /// Clears `flags`
+                ///
+                /// Only `NACKF`, `STOPF`, `BERR` and `ARLO` can be cleared; the other flags follow
+                /// the data registers and the bus, and are ignored
+                pub fn clear_flags(&mut self, flags: Flags) {
+                    // ICR bits sit at the same positions as the ISR flags they clear
+                    let bits = (flags & Flags::clearable()).bits();
+                    self.i2c.icr.write(|w| unsafe { w.bits(bits) });
+                }
+
+                /// Returns the event interrupt of this I2C peripheral
+                pub fn interrupt(&self) -> Interrupt {
+                    Interrupt::$EV
+                }
+
+                /// Returns the error interrupt of this I2C peripheral
+                pub fn error_interrupt(&self) -> Interrupt {
+                    Interrupt::$ER
+                }
+
                 /// Releases the I2C peripheral and associated pins
                 pub fn free(self) -> ($I2CX, (SCL, SDA)) {
                     (self.i2c, self.pins)
@@ -290,6 +568,61 @@ macro_rules! hal {
 }
 
 hal! {
-    I2C1: (i2c1, i2c1en, i2c1rst),
-    I2C2: (i2c2, i2c2en, i2c2rst),
+    I2C1: (i2c1, i2c1_fast_mode_plus, i2c1en, i2c1rst, i2c1sw, I2C1, I2C1_EV_EXTI23, I2C1_ER),
+    I2C2: (i2c2, i2c2_fast_mode_plus, i2c2en, i2c2rst, i2c2sw, I2C2, I2C2_EV_EXTI24, I2C2_ER),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{timing, Flags, Timing};
+
+    #[test]
+    fn flags() {
+        // TXE, TXIS, RXNE, NACKF, STOPF, TC, TCR, BERR, ARLO and BUSY, as in I2C_ISR
+        assert_eq!(Flags::all().bits(), 0b1000_0011_1111_0111);
+        assert_eq!(Flags::NACKF.bits(), 1 << 4);
+        assert_eq!(Flags::BERR.bits(), 1 << 8);
+        assert_eq!(Flags::BUSY.bits(), 1 << 15);
+    }
+
+    #[test]
+    fn clearable_flags() {
+        // NACKCF, STOPCF, BERRCF and ARLOCF
+        assert_eq!(Flags::clearable().bits(), 0b11_0011_0000);
+        assert!(!Flags::clearable().contains(Flags::RXNE));
+    }
+
+    fn t(presc: u8, scll: u8, sclh: u8, sdadel: u8, scldel: u8) -> Option<Timing> {
+        Some(Timing {
+            presc,
+            scll,
+            sclh,
+            sdadel,
+            scldel,
+        })
+    }
+
+    #[test]
+    fn timings() {
+        // HSI
+        assert_eq!(timing(8_000_000, 100_000), t(0, 49, 24, 1, 3));
+        assert_eq!(timing(8_000_000, 400_000), t(0, 9, 4, 1, 3));
+        assert_eq!(timing(8_000_000, 1_000_000), t(0, 1, 0, 0, 1));
+
+        assert_eq!(timing(48_000_000, 100_000), t(1, 157, 78, 3, 11));
+        assert_eq!(timing(48_000_000, 400_000), t(1, 37, 18, 3, 11));
+        assert_eq!(timing(48_000_000, 1_000_000), t(0, 27, 13, 0, 11));
+
+        assert_eq!(timing(64_000_000, 100_000), t(1, 211, 105, 4, 15));
+        assert_eq!(timing(64_000_000, 400_000), t(1, 51, 25, 4, 15));
+        assert_eq!(timing(64_000_000, 1_000_000), t(0, 39, 19, 0, 15));
+
+        // SCLDEL doesn't fit in 4 bits without prescaling
+        assert_eq!(timing(72_000_000, 100_000), t(2, 157, 78, 3, 11));
+        assert_eq!(timing(72_000_000, 400_000), t(2, 37, 18, 3, 11));
+        assert_eq!(timing(72_000_000, 1_000_000), t(1, 21, 10, 0, 8));
+
+        // too slow
+        assert_eq!(timing(4_000_000, 1_000_000), None);
+    }
 }
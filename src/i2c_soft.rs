@@ -0,0 +1,229 @@
+//! Bit-banged I2C bus master
+//!
+//! For sensors wired to pins that can't be routed to an I2C peripheral. Works on top of any two
+//! open drain pins, e.g. `PXi<Output<OpenDrain>>` with `internal_pull_up` enabled (or external
+//! pull-up resistors, which allow faster edges), and a delay source. The bus runs at roughly 100
+//! KHz, or slower if the delay source is coarse; slaves that stretch the clock by holding SCL low
+//! are waited for. Only 7-bit addresses are supported.
+
+use hal::blocking::delay::DelayUs;
+use hal::blocking::i2c::{Read, Write, WriteRead};
+use hal::digital::{InputPin, OutputPin};
+
+/// Bit-banged I2C error
+#[derive(Debug)]
+pub enum Error {
+    /// The slave didn't acknowledge its address or a byte
+    Nack,
+    /// A slave has been stretching the clock for more than 1 ms
+    Timeout,
+    #[doc(hidden)]
+    _Extensible,
+}
+
+/// Half of an SCL period, in microseconds
+const HALF_PERIOD: u8 = 5;
+
+/// Maximum time a slave may stretch the clock for, in microseconds
+const STRETCH_TIMEOUT: u16 = 1_000;
+
+/// Bit-banged I2C bus master
+pub struct I2cSoft<SCL, SDA, D> {
+    scl: SCL,
+    sda: SDA,
+    delay: D,
+}
+
+impl<SCL, SDA, D> I2cSoft<SCL, SDA, D>
+where
+    SCL: InputPin + OutputPin,
+    SDA: InputPin + OutputPin,
+    D: DelayUs<u8>,
+{
+    /// Creates a bus master that drives the `scl` and `sda` lines
+    pub fn new(mut scl: SCL, mut sda: SDA, delay: D) -> Self {
+        // release the bus
+        sda.set_high();
+        scl.set_high();
+
+        I2cSoft { scl, sda, delay }
+    }
+
+    /// Releases the pins and the delay source
+    pub fn free(self) -> (SCL, SDA, D) {
+        (self.scl, self.sda, self.delay)
+    }
+
+    fn wait(&mut self) {
+        self.delay.delay_us(HALF_PERIOD);
+    }
+
+    /// Releases SCL and waits until no slave is stretching the clock
+    fn scl_high(&mut self) -> Result<(), Error> {
+        self.scl.set_high();
+
+        let mut stretched = 0;
+        while self.scl.is_low() {
+            if stretched == STRETCH_TIMEOUT {
+                return Err(Error::Timeout);
+            }
+
+            self.delay.delay_us(1);
+            stretched += 1;
+        }
+
+        Ok(())
+    }
+
+    fn start(&mut self) -> Result<(), Error> {
+        // also works as a repeated START: SCL is low after the previous byte
+        self.sda.set_high();
+        self.wait();
+        self.scl_high()?;
+        self.wait();
+        self.sda.set_low();
+        self.wait();
+        self.scl.set_low();
+
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), Error> {
+        self.sda.set_low();
+        self.wait();
+        self.scl_high()?;
+        self.wait();
+        self.sda.set_high();
+        self.wait();
+
+        Ok(())
+    }
+
+    fn write_bit(&mut self, bit: bool) -> Result<(), Error> {
+        if bit {
+            self.sda.set_high();
+        } else {
+            self.sda.set_low();
+        }
+        self.wait();
+        self.scl_high()?;
+        self.wait();
+        self.scl.set_low();
+
+        Ok(())
+    }
+
+    fn read_bit(&mut self) -> Result<bool, Error> {
+        self.sda.set_high();
+        self.wait();
+        self.scl_high()?;
+        self.wait();
+        let bit = self.sda.is_high();
+        self.scl.set_low();
+
+        Ok(bit)
+    }
+
+    /// Writes a byte, MSB first, and returns `Error::Nack` if it's not acknowledged
+    fn write_byte(&mut self, byte: u8) -> Result<(), Error> {
+        for i in (0..8).rev() {
+            self.write_bit(byte & (1 << i) != 0)?;
+        }
+
+        if self.read_bit()? {
+            Err(Error::Nack)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Reads a byte, MSB first, and acknowledges it if `ack` is set
+    fn read_byte(&mut self, ack: bool) -> Result<u8, Error> {
+        let mut byte = 0;
+        for _ in 0..8 {
+            byte = (byte << 1) | u8::from(self.read_bit()?);
+        }
+
+        self.write_bit(!ack)?;
+
+        Ok(byte)
+    }
+
+    fn write_bytes(&mut self, addr: u8, bytes: &[u8]) -> Result<(), Error> {
+        self.start()?;
+        self.write_byte(addr << 1)?;
+        for byte in bytes {
+            self.write_byte(*byte)?;
+        }
+
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, addr: u8, buffer: &mut [u8]) -> Result<(), Error> {
+        self.start()?;
+        self.write_byte((addr << 1) | 1)?;
+
+        let last = buffer.len().saturating_sub(1);
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            // NACK the last byte to end the read
+            *byte = self.read_byte(i != last)?;
+        }
+
+        Ok(())
+    }
+
+    /// Issues a STOP condition whatever the outcome of `result`, and returns `result`
+    fn end(&mut self, result: Result<(), Error>) -> Result<(), Error> {
+        let stop = self.stop();
+
+        result.and(stop)
+    }
+}
+
+impl<SCL, SDA, D> Write for I2cSoft<SCL, SDA, D>
+where
+    SCL: InputPin + OutputPin,
+    SDA: InputPin + OutputPin,
+    D: DelayUs<u8>,
+{
+    type Error = Error;
+
+    fn write(&mut self, addr: u8, bytes: &[u8]) -> Result<(), Error> {
+        let result = self.write_bytes(addr, bytes);
+
+        self.end(result)
+    }
+}
+
+impl<SCL, SDA, D> Read for I2cSoft<SCL, SDA, D>
+where
+    SCL: InputPin + OutputPin,
+    SDA: InputPin + OutputPin,
+    D: DelayUs<u8>,
+{
+    type Error = Error;
+
+    fn read(&mut self, addr: u8, buffer: &mut [u8]) -> Result<(), Error> {
+        let result = self.read_bytes(addr, buffer);
+
+        self.end(result)
+    }
+}
+
+impl<SCL, SDA, D> WriteRead for I2cSoft<SCL, SDA, D>
+where
+    SCL: InputPin + OutputPin,
+    SDA: InputPin + OutputPin,
+    D: DelayUs<u8>,
+{
+    type Error = Error;
+
+    fn write_read(&mut self, addr: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), Error> {
+        // repeated START between the write and the read
+        let result = self
+            .write_bytes(addr, bytes)
+            .and_then(|_| self.read_bytes(addr, buffer));
+
+        self.end(result)
+    }
+}
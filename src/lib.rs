@@ -34,13 +34,27 @@ extern crate nb;
 pub extern crate stm32f30x;
 extern crate void;
 
+#[macro_use]
+mod macros;
+
+pub mod blink;
 pub mod delay;
 pub mod flash;
 pub mod gpio;
+pub mod hd44780;
 pub mod i2c;
+pub mod i2c_soft;
+pub mod onewire;
+#[cfg(feature = "panic-serial")]
+pub mod panic_serial;
 pub mod prelude;
 pub mod rcc;
+pub mod scheduler;
 pub mod serial;
+pub mod signature;
+pub mod soft_pwm;
 pub mod spi;
+pub mod syscfg;
 pub mod time;
 pub mod timer;
+pub mod ws2812;
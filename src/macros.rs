@@ -0,0 +1,106 @@
+/// Defines a set of status flags backed by the bits of a status register
+///
+/// The flags are associated constants that can be combined with `|` and tested with `contains`
+macro_rules! flags {
+    (
+        $(#[$attr:meta])*
+        pub struct $Flags:ident {
+            $(
+                $(#[$flag_attr:meta])*
+                const $FLAG:ident = $bit:expr;
+            )+
+        }
+    ) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        pub struct $Flags {
+            bits: u32,
+        }
+
+        impl $Flags {
+            $(
+                $(#[$flag_attr])*
+                pub const $FLAG: $Flags = $Flags { bits: 1 << $bit };
+            )+
+
+            /// No flag
+            pub fn empty() -> Self {
+                $Flags { bits: 0 }
+            }
+
+            /// Every flag
+            pub fn all() -> Self {
+                $Flags {
+                    bits: 0 $(| (1 << $bit))+,
+                }
+            }
+
+            /// Keeps the bits of a raw register value that are flags of this set
+            pub fn from_bits_truncate(bits: u32) -> Self {
+                $Flags {
+                    bits: bits & Self::all().bits,
+                }
+            }
+
+            /// Returns the flags as register bits
+            pub fn bits(&self) -> u32 {
+                self.bits
+            }
+
+            /// Returns `true` if all the flags of `other` are set
+            pub fn contains(&self, other: Self) -> bool {
+                self.bits & other.bits == other.bits
+            }
+
+            /// Returns `true` if no flag is set
+            pub fn is_empty(&self) -> bool {
+                self.bits == 0
+            }
+        }
+
+        impl ::core::ops::BitOr for $Flags {
+            type Output = Self;
+
+            fn bitor(self, other: Self) -> Self {
+                $Flags {
+                    bits: self.bits | other.bits,
+                }
+            }
+        }
+
+        impl ::core::ops::BitAnd for $Flags {
+            type Output = Self;
+
+            fn bitand(self, other: Self) -> Self {
+                $Flags {
+                    bits: self.bits & other.bits,
+                }
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    flags! {
+        /// Test flags
+        pub struct Flags {
+            /// Bit 0
+            const A = 0;
+            /// Bit 3
+            const B = 3;
+        }
+    }
+
+    #[test]
+    fn flags() {
+        assert_eq!(Flags::A.bits(), 0b0001);
+        assert_eq!(Flags::B.bits(), 0b1000);
+        assert_eq!(Flags::all(), Flags::A | Flags::B);
+        assert_eq!(Flags::from_bits_truncate(0b1110), Flags::B);
+        assert!(Flags::all().contains(Flags::A));
+        assert!(!Flags::B.contains(Flags::A | Flags::B));
+        assert!((Flags::A & Flags::B).is_empty());
+        assert!(Flags::empty().is_empty());
+    }
+}
@@ -0,0 +1,250 @@
+//! Bit-banged 1-Wire bus master
+//!
+//! Works on top of any open drain pin, e.g. a `PXi<Output<OpenDrain>>`, with either an external
+//! pull-up resistor (4.7 KOhm is the usual value) or the internal pull-up enabled through
+//! `internal_pull_up`. The bus timings are produced by the `delay` argument so they are only as
+//! accurate as that delay source; interrupts that fire in the middle of a time slot will corrupt
+//! it, so consider running bus transactions inside a critical section.
+
+use hal::blocking::delay::DelayUs;
+use hal::digital::{InputPin, OutputPin};
+
+/// 1-Wire error
+#[derive(Debug)]
+pub enum Error {
+    /// No device answered the reset pulse
+    NoPresence,
+    /// The CRC of a ROM code read from the bus doesn't match
+    Crc,
+    #[doc(hidden)]
+    _Extensible,
+}
+
+/// 1-Wire ROM command: Search ROM
+const SEARCH_ROM: u8 = 0xf0;
+
+/// 1-Wire bus master
+pub struct OneWire<PIN> {
+    pin: PIN,
+}
+
+/// State of an ongoing ROM search (see `OneWire::search`)
+pub struct Search {
+    rom: [u8; 8],
+    last_discrepancy: u8,
+    done: bool,
+}
+
+impl Search {
+    /// Starts a new search
+    pub fn new() -> Self {
+        Search {
+            rom: [0; 8],
+            last_discrepancy: 0,
+            done: false,
+        }
+    }
+}
+
+impl Default for Search {
+    fn default() -> Self {
+        Search::new()
+    }
+}
+
+impl<PIN> OneWire<PIN>
+where
+    PIN: InputPin + OutputPin,
+{
+    /// Creates a bus master that drives `pin`
+    pub fn new(mut pin: PIN) -> Self {
+        // release the bus
+        pin.set_high();
+
+        OneWire { pin }
+    }
+
+    /// Issues a reset pulse and returns `true` if at least one device answered with a presence
+    /// pulse
+    pub fn reset<D>(&mut self, delay: &mut D) -> bool
+    where
+        D: DelayUs<u16>,
+    {
+        self.pin.set_low();
+        delay.delay_us(480);
+        self.pin.set_high();
+        delay.delay_us(70);
+        let presence = self.pin.is_low();
+        delay.delay_us(410);
+
+        presence
+    }
+
+    /// Writes a single bit
+    pub fn write_bit<D>(&mut self, delay: &mut D, bit: bool)
+    where
+        D: DelayUs<u16>,
+    {
+        if bit {
+            self.pin.set_low();
+            delay.delay_us(6);
+            self.pin.set_high();
+            delay.delay_us(64);
+        } else {
+            self.pin.set_low();
+            delay.delay_us(60);
+            self.pin.set_high();
+            delay.delay_us(10);
+        }
+    }
+
+    /// Reads a single bit
+    pub fn read_bit<D>(&mut self, delay: &mut D) -> bool
+    where
+        D: DelayUs<u16>,
+    {
+        self.pin.set_low();
+        delay.delay_us(6);
+        self.pin.set_high();
+        delay.delay_us(9);
+        let bit = self.pin.is_high();
+        delay.delay_us(55);
+
+        bit
+    }
+
+    /// Writes a byte, LSB first
+    pub fn write_byte<D>(&mut self, delay: &mut D, byte: u8)
+    where
+        D: DelayUs<u16>,
+    {
+        for i in 0..8 {
+            self.write_bit(delay, byte & (1 << i) != 0);
+        }
+    }
+
+    /// Reads a byte, LSB first
+    pub fn read_byte<D>(&mut self, delay: &mut D) -> u8
+    where
+        D: DelayUs<u16>,
+    {
+        let mut byte = 0;
+        for i in 0..8 {
+            if self.read_bit(delay) {
+                byte |= 1 << i;
+            }
+        }
+
+        byte
+    }
+
+    /// Finds the next device on the bus and returns its ROM code
+    ///
+    /// Call it repeatedly with the same `search` to enumerate all the devices; `Ok(None)` is
+    /// returned once all of them have been found.
+    pub fn search<D>(
+        &mut self,
+        delay: &mut D,
+        search: &mut Search,
+    ) -> Result<Option<[u8; 8]>, Error>
+    where
+        D: DelayUs<u16>,
+    {
+        if search.done {
+            return Ok(None);
+        }
+
+        if !self.reset(delay) {
+            return Err(Error::NoPresence);
+        }
+
+        self.write_byte(delay, SEARCH_ROM);
+
+        let mut last_zero = 0;
+        for n in 1..65 {
+            let byte = usize::from((n - 1) / 8);
+            let mask = 1 << ((n - 1) % 8);
+
+            let bit = self.read_bit(delay);
+            let complement = self.read_bit(delay);
+
+            let direction = match (bit, complement) {
+                // no device took part in this step of the search
+                (true, true) => return Err(Error::NoPresence),
+                // all the remaining devices agree on this bit
+                (true, false) => true,
+                (false, true) => false,
+                // discrepancy: devices with both values are present
+                (false, false) => {
+                    let direction = if n < search.last_discrepancy {
+                        search.rom[byte] & mask != 0
+                    } else {
+                        n == search.last_discrepancy
+                    };
+
+                    if !direction {
+                        last_zero = n;
+                    }
+
+                    direction
+                }
+            };
+
+            if direction {
+                search.rom[byte] |= mask;
+            } else {
+                search.rom[byte] &= !mask;
+            }
+
+            self.write_bit(delay, direction);
+        }
+
+        search.last_discrepancy = last_zero;
+        search.done = last_zero == 0;
+
+        if crc8(&search.rom[..7]) != search.rom[7] {
+            return Err(Error::Crc);
+        }
+
+        Ok(Some(search.rom))
+    }
+
+    /// Releases the pin
+    pub fn free(self) -> PIN {
+        self.pin
+    }
+}
+
+/// Computes the Dallas / Maxim CRC-8 (polynomial x^8 + x^5 + x^4 + 1) of `bytes`
+///
+/// The CRC of a full ROM code, or of a scratchpad including its CRC byte, is 0.
+pub fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0;
+    for byte in bytes {
+        let mut byte = *byte;
+        for _ in 0..8 {
+            let mix = (crc ^ byte) & 1;
+            crc >>= 1;
+            if mix != 0 {
+                crc ^= 0x8c;
+            }
+            byte >>= 1;
+        }
+    }
+
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::crc8;
+
+    #[test]
+    fn crc8_rom_code() {
+        // example ROM code from Maxim's application note 27
+        let rom = [0x02, 0x1c, 0xb8, 0x01, 0x00, 0x00, 0x00, 0xa2];
+
+        assert_eq!(crc8(&rom[..7]), 0xa2);
+        assert_eq!(crc8(&rom), 0);
+    }
+}
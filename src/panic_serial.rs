@@ -0,0 +1,66 @@
+//! Panic handler that reports the panic message over a serial port
+//!
+//! Enabled by the "panic-serial" Cargo feature. Hand over the `Tx` half of an initialized `Serial`
+//! to `set_panic_tx`; when the program panics the message is sent over it and then the processor
+//! halts with the interrupts disabled. If no `Tx` has been registered the processor just halts.
+//!
+//! Don't enable this feature if the application, or another crate, provides its own
+//! `#[panic_handler]`.
+
+use core::cell::Cell;
+use core::fmt::{self, Write};
+use core::mem;
+#[cfg(not(test))]
+use core::panic::PanicInfo;
+
+use cortex_m::interrupt::{self, Mutex};
+
+static WRITE: Mutex<Cell<Option<fn(u8)>>> = Mutex::new(Cell::new(None));
+
+// FIXME these should be "closed" traits
+/// Transmitter that can report panics -- DO NOT IMPLEMENT THIS TRAIT
+pub unsafe trait PanicTx {
+    #[doc(hidden)]
+    fn write_byte(byte: u8);
+}
+
+/// Makes the panic handler report the panic message over `tx`
+///
+/// The transmitter is consumed; it's used again only if the program panics.
+pub fn set_panic_tx<TX>(tx: TX)
+where
+    TX: PanicTx,
+{
+    // the transmitter is a zero sized token; the panic handler conjures a new one
+    mem::forget(tx);
+
+    interrupt::free(|cs| WRITE.borrow(cs).set(Some(TX::write_byte)));
+}
+
+struct Writer(fn(u8));
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            (self.0)(byte);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    interrupt::disable();
+
+    // NOTE(unsafe) the interrupts are disabled for good
+    let cs = unsafe { &interrupt::CriticalSection::new() };
+
+    if let Some(write) = WRITE.borrow(cs).get() {
+        let mut writer = Writer(write);
+        writeln!(writer, "{}", info).ok();
+    }
+
+    loop {}
+}
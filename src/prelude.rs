@@ -4,4 +4,5 @@ pub use flash::FlashExt as _stm32f30x_hal_flash_FlashExt;
 pub use gpio::GpioExt as _stm32f30x_hal_gpio_GpioExt;
 pub use hal::prelude::*;
 pub use rcc::RccExt as _stm32f30x_hal_rcc_RccExt;
+pub use syscfg::SyscfgExt as _stm32f30x_hal_syscfg_SyscfgExt;
 pub use time::U32Ext as _stm32f30x_hal_time_U32Ext;
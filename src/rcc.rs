@@ -1,9 +1,25 @@
 //! Reset and Clock Control
+//!
+//! # `test-util`
+//!
+//! The "test-util" Cargo feature adds `unsafe fn steal` constructors to the `AHB`, `APB1`,
+//! `APB2` and `BDCR` tokens, and to `flash::ACR`, for on-target test harnesses that need a token
+//! without owning the `RCC` / `FLASH` peripheral. The stolen tokens still dereference the real
+//! registers, so they can't be used off-device, and they may alias the ones returned by
+//! `constrain`; the caller must make sure the two don't fight over the registers.
 
-use core::cmp;
+#[cfg(feature = "global-clocks")]
+use core::cell::Cell;
+use core::{cmp, fmt};
 
 use cast::u32;
-use stm32f30x::{rcc, RCC};
+#[cfg(feature = "global-clocks")]
+use cortex_m::interrupt::{self, Mutex};
+use stm32f30x::{
+    rcc, ADC1_2, ADC3_4, CAN, DAC, GPIOA, GPIOB, GPIOC, GPIOD, GPIOE, GPIOF, I2C1, I2C2, PWR, RCC,
+    SPI1, SPI2, SPI3, SYSCFG, TIM1, TIM15, TIM16, TIM17, TIM2, TIM3, TIM4, TIM6, TIM7, TIM8, TSC,
+    UART4, UART5, USART1, USART2, USART3, USB_FS, WWDG,
+};
 
 use flash::ACR;
 use time::Hertz;
@@ -20,8 +36,12 @@ impl RccExt for RCC {
             ahb: AHB { _0: () },
             apb1: APB1 { _0: () },
             apb2: APB2 { _0: () },
+            bdcr: BDCR { _0: () },
             cfgr: CFGR {
                 hclk: None,
+                i2c1: None,
+                i2c2: None,
+                mco: None,
                 pclk1: None,
                 pclk2: None,
                 sysclk: None,
@@ -38,6 +58,8 @@ pub struct Rcc {
     pub apb1: APB1,
     /// Advanced Peripheral Bus 2 (APB2) registers
     pub apb2: APB2,
+    /// Backup domain control register
+    pub bdcr: BDCR,
     /// Clock configuration
     pub cfgr: CFGR,
 }
@@ -48,6 +70,12 @@ pub struct AHB {
 }
 
 impl AHB {
+    /// Creates a new token without going through `RCC.constrain()`; see the module documentation
+    #[cfg(feature = "test-util")]
+    pub unsafe fn steal() -> Self {
+        AHB { _0: () }
+    }
+
     pub(crate) fn enr(&mut self) -> &rcc::AHBENR {
         // NOTE(unsafe) this proxy grants exclusive access to this register
         unsafe { &(*RCC::ptr()).ahbenr }
@@ -57,6 +85,21 @@ impl AHB {
         // NOTE(unsafe) this proxy grants exclusive access to this register
         unsafe { &(*RCC::ptr()).ahbrstr }
     }
+
+    /// Enables the clock of `peripheral` and pulses its reset line, leaving it in its reset state
+    ///
+    /// This is the sequence the driver constructors (and `split`) run, e.g. to bring a
+    /// misbehaving peripheral back to a clean slate
+    pub fn reset_and_enable<P>(&mut self, _peripheral: &P)
+    where
+        P: AhbPeripheral,
+    {
+        let mask = 1 << P::BIT;
+
+        self.enr().modify(|r, w| unsafe { w.bits(r.bits() | mask) });
+        self.rstr().modify(|r, w| unsafe { w.bits(r.bits() | mask) });
+        self.rstr().modify(|r, w| unsafe { w.bits(r.bits() & !mask) });
+    }
 }
 
 /// Advanced Peripheral Bus 1 (APB1) registers
@@ -65,6 +108,12 @@ pub struct APB1 {
 }
 
 impl APB1 {
+    /// Creates a new token without going through `RCC.constrain()`; see the module documentation
+    #[cfg(feature = "test-util")]
+    pub unsafe fn steal() -> Self {
+        APB1 { _0: () }
+    }
+
     pub(crate) fn enr(&mut self) -> &rcc::APB1ENR {
         // NOTE(unsafe) this proxy grants exclusive access to this register
         unsafe { &(*RCC::ptr()).apb1enr }
@@ -74,6 +123,21 @@ impl APB1 {
         // NOTE(unsafe) this proxy grants exclusive access to this register
         unsafe { &(*RCC::ptr()).apb1rstr }
     }
+
+    /// Enables the clock of `peripheral` and pulses its reset line, leaving it in its reset state
+    ///
+    /// This is the sequence the driver constructors (and `split`) run, e.g. to bring a
+    /// misbehaving peripheral back to a clean slate
+    pub fn reset_and_enable<P>(&mut self, _peripheral: &P)
+    where
+        P: Apb1Peripheral,
+    {
+        let mask = 1 << P::BIT;
+
+        self.enr().modify(|r, w| unsafe { w.bits(r.bits() | mask) });
+        self.rstr().modify(|r, w| unsafe { w.bits(r.bits() | mask) });
+        self.rstr().modify(|r, w| unsafe { w.bits(r.bits() & !mask) });
+    }
 }
 
 /// Advanced Peripheral Bus 2 (APB2) registers
@@ -82,6 +146,12 @@ pub struct APB2 {
 }
 
 impl APB2 {
+    /// Creates a new token without going through `RCC.constrain()`; see the module documentation
+    #[cfg(feature = "test-util")]
+    pub unsafe fn steal() -> Self {
+        APB2 { _0: () }
+    }
+
     pub(crate) fn enr(&mut self) -> &rcc::APB2ENR {
         // NOTE(unsafe) this proxy grants exclusive access to this register
         unsafe { &(*RCC::ptr()).apb2enr }
@@ -91,13 +161,290 @@ impl APB2 {
         // NOTE(unsafe) this proxy grants exclusive access to this register
         unsafe { &(*RCC::ptr()).apb2rstr }
     }
+
+    /// Enables the clock of `peripheral` and pulses its reset line, leaving it in its reset state
+    ///
+    /// This is the sequence the driver constructors (and `split`) run, e.g. to bring a
+    /// misbehaving peripheral back to a clean slate
+    pub fn reset_and_enable<P>(&mut self, _peripheral: &P)
+    where
+        P: Apb2Peripheral,
+    {
+        let mask = 1 << P::BIT;
+
+        self.enr().modify(|r, w| unsafe { w.bits(r.bits() | mask) });
+        self.rstr().modify(|r, w| unsafe { w.bits(r.bits() | mask) });
+        self.rstr().modify(|r, w| unsafe { w.bits(r.bits() & !mask) });
+    }
+}
+
+// FIXME these should be "closed" traits
+/// Peripheral on the AHB -- DO NOT IMPLEMENT THIS TRAIT
+pub unsafe trait AhbPeripheral {
+    #[doc(hidden)]
+    const BIT: u8;
+}
+
+/// Peripheral on the APB1 -- DO NOT IMPLEMENT THIS TRAIT
+pub unsafe trait Apb1Peripheral {
+    #[doc(hidden)]
+    const BIT: u8;
+}
+
+/// Peripheral on the APB2 -- DO NOT IMPLEMENT THIS TRAIT
+pub unsafe trait Apb2Peripheral {
+    #[doc(hidden)]
+    const BIT: u8;
+}
+
+macro_rules! bus_peripherals {
+    ($Bus:ident: $($PER:ident: $bit:expr,)+) => {
+        $(
+            unsafe impl $Bus for $PER {
+                const BIT: u8 = $bit;
+            }
+        )+
+    }
+}
+
+// NOTE the DMA controllers and the CRC unit have no reset line
+bus_peripherals! {
+    AhbPeripheral:
+    GPIOA: 17,
+    GPIOB: 18,
+    GPIOC: 19,
+    GPIOD: 20,
+    GPIOE: 21,
+    GPIOF: 22,
+    TSC: 24,
+    ADC1_2: 28,
+    ADC3_4: 29,
+}
+
+bus_peripherals! {
+    Apb1Peripheral:
+    TIM2: 0,
+    TIM3: 1,
+    TIM4: 2,
+    TIM6: 4,
+    TIM7: 5,
+    WWDG: 11,
+    SPI2: 14,
+    SPI3: 15,
+    USART2: 17,
+    USART3: 18,
+    UART4: 19,
+    UART5: 20,
+    I2C1: 21,
+    I2C2: 22,
+    USB_FS: 23,
+    CAN: 25,
+    PWR: 28,
+    DAC: 29,
+}
+
+bus_peripherals! {
+    Apb2Peripheral:
+    SYSCFG: 0,
+    TIM1: 11,
+    SPI1: 12,
+    TIM8: 13,
+    USART1: 14,
+    TIM15: 16,
+    TIM16: 17,
+    TIM17: 18,
+}
+
+/// Backup domain control register
+pub struct BDCR {
+    _0: (),
+}
+
+impl BDCR {
+    /// Creates a new token without going through `RCC.constrain()`; see the module documentation
+    #[cfg(feature = "test-util")]
+    pub unsafe fn steal() -> Self {
+        BDCR { _0: () }
+    }
+
+    pub(crate) fn bdcr(&mut self) -> &rcc::BDCR {
+        // NOTE(unsafe) this proxy grants exclusive access to this register
+        unsafe { &(*RCC::ptr()).bdcr }
+    }
+
+    /// Starts the low speed external (LSE) 32.768 KHz oscillator
+    ///
+    /// Set `bypass` if OSC32_IN is driven by an external clock rather than a crystal. This
+    /// enables write access to the backup domain (PWR_CR.DBP), where the LSE configuration lives,
+    /// and leaves it enabled. It doesn't wait for the oscillator to stabilize; see `wait_lse`.
+    ///
+    /// Returns `ClockError::Timeout(Clock::Lse)` if an already running LSE doesn't stop in time
+    pub fn enable_lse(
+        &mut self,
+        bypass: bool,
+        pwr: &mut PWR,
+        apb1: &mut APB1,
+    ) -> Result<(), ClockError> {
+        apb1.enr().modify(|_, w| w.pwren().set_bit());
+        pwr.cr.modify(|_, w| w.dbp().set_bit());
+
+        // LSEBYP can only be changed while the oscillator is off
+        self.bdcr().modify(|_, w| w.lseon().clear_bit());
+        wait_clock(Clock::Lse, || self.bdcr().read().lserdy().bit_is_clear())?;
+        self.bdcr().modify(|_, w| w.lsebyp().bit(bypass));
+        self.bdcr().modify(|_, w| w.lseon().set_bit());
+
+        Ok(())
+    }
+
+    /// Returns `true` once the LSE oscillator is stable
+    pub fn lse_ready(&mut self) -> bool {
+        self.bdcr().read().lserdy().bit_is_set()
+    }
+
+    /// Waits, polling `lse_ready` at most `retries` times, for the LSE oscillator to stabilize
+    ///
+    /// A crystal can take up to a couple of seconds to start, far longer than the
+    /// `READY_RETRIES` polls `freeze` allows for the fast clocks, so the bound is left to the
+    /// caller. Returns `ClockError::Timeout(Clock::Lse)` if the oscillator isn't ready in time.
+    pub fn wait_lse(&mut self, retries: u32) -> Result<(), ClockError> {
+        wait_ready(|| self.lse_ready(), retries).map_err(|_| ClockError::Timeout(Clock::Lse))
+    }
+
+    /// Clocks the RTC from the LSE oscillator and enables it
+    ///
+    /// The RTC clock source can only be selected once; after that it can only be changed by
+    /// resetting the whole backup domain. Requires `enable_lse` to have been called.
+    pub fn rtc_use_lse(&mut self) {
+        // RTCSEL = 0b01: LSE
+        self.bdcr()
+            .modify(|_, w| unsafe { w.rtcsel().bits(0b01).rtcen().set_bit() });
+    }
 }
 
-const HSI: u32 = 8_000_000; // Hz
+pub(crate) const HSI: u32 = 8_000_000; // Hz
+
+/// Number of times a clock ready flag is polled by `freeze` before giving up
+pub const READY_RETRIES: u32 = 0x5000;
+
+/// Clock that failed to become ready
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Clock {
+    /// High speed internal (HSI) RC oscillator, as the system clock
+    Hsi,
+    /// Phase locked loop (PLL), either locking / unlocking or as the system clock
+    Pll,
+    /// Low speed external (LSE) 32.768 KHz oscillator
+    Lse,
+}
+
+/// Clock configuration error
+#[derive(Debug)]
+pub enum ClockError {
+    /// A clock didn't become ready in time
+    Timeout(Clock),
+    #[doc(hidden)]
+    _Extensible,
+}
+
+/// A ready flag didn't get set in time
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timeout;
+
+/// Polls `ready` until it returns `true`, at most `retries` times
+///
+/// This is the bounded wait `freeze` uses (with `READY_RETRIES`) for the clock ready flags, and
+/// `BDCR` uses to start and stop the LSE; it can be used to bring up other clocks without risking
+/// a hang if the oscillator is broken or missing. Each poll takes a few core clock cycles, so the
+/// time out scales with SYSCLK.
+pub fn wait_ready<F>(mut ready: F, retries: u32) -> Result<(), Timeout>
+where
+    F: FnMut() -> bool,
+{
+    for _ in 0..retries {
+        if ready() {
+            return Ok(());
+        }
+    }
+
+    Err(Timeout)
+}
+
+/// `wait_ready` with the retries `freeze` uses, reporting which `clock` timed out
+fn wait_clock<F>(clock: Clock, ready: F) -> Result<(), ClockError>
+where
+    F: FnMut() -> bool,
+{
+    wait_ready(ready, READY_RETRIES).map_err(|_| ClockError::Timeout(clock))
+}
+
+/// Microcontroller clock output (MCO) source
+#[derive(Clone, Copy)]
+pub enum Mco {
+    /// Low speed internal (LSI) RC oscillator
+    Lsi,
+    /// Low speed external (LSE) 32.768 KHz oscillator
+    Lse,
+    /// System clock
+    Sysclk,
+    /// High speed internal (HSI) RC oscillator
+    Hsi,
+    /// High speed external (HSE) oscillator
+    Hse,
+    /// PLL clock divided by 2
+    PllDiv2,
+}
+
+impl Mco {
+    fn bits(&self) -> u8 {
+        match *self {
+            Mco::Lsi => 0b010,
+            Mco::Lse => 0b011,
+            Mco::Sysclk => 0b100,
+            Mco::Hsi => 0b101,
+            Mco::Hse => 0b110,
+            Mco::PllDiv2 => 0b111,
+        }
+    }
+}
+
+/// Kernel clock of an I2C peripheral (RCC_CFGR3.I2CxSW)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum I2cClock {
+    /// High speed internal (HSI) RC oscillator, 8 MHz; the reset value
+    Hsi,
+    /// System clock
+    Sysclk,
+}
+
+/// System clock source
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SysClkSource {
+    /// High speed internal (HSI) RC oscillator
+    Hsi,
+    /// High speed external (HSE) oscillator
+    Hse,
+    /// PLL
+    Pll,
+}
+
+/// Returns the oscillator the system clock is currently running from (RCC_CFGR.SWS)
+pub fn sysclk_source() -> SysClkSource {
+    // NOTE(unsafe) atomic read with no side effects
+    match unsafe { (*RCC::ptr()).cfgr.read().sws().bits() } {
+        0b00 => SysClkSource::Hsi,
+        0b01 => SysClkSource::Hse,
+        0b10 => SysClkSource::Pll,
+        _ => unreachable!(),
+    }
+}
 
 /// Clock configuration
 pub struct CFGR {
     hclk: Option<u32>,
+    i2c1: Option<I2cClock>,
+    i2c2: Option<I2cClock>,
+    mco: Option<Mco>,
     pclk1: Option<u32>,
     pclk2: Option<u32>,
     sysclk: Option<u32>,
@@ -113,6 +460,31 @@ impl CFGR {
         self
     }
 
+    /// Selects the clock the I2C1 peripheral derives its bus timings from
+    ///
+    /// Fast-mode Plus needs an I2C clock of at least 16 MHz, so SYSCLK must be selected for it.
+    /// Left untouched (the HSI, out of reset) if not set.
+    pub fn i2c1_clock(mut self, source: I2cClock) -> Self {
+        self.i2c1 = Some(source);
+        self
+    }
+
+    /// Selects the clock the I2C2 peripheral derives its bus timings from (see `i2c1_clock`)
+    pub fn i2c2_clock(mut self, source: I2cClock) -> Self {
+        self.i2c2 = Some(source);
+        self
+    }
+
+    /// Outputs the `source` clock on the MCO pin (PA8 configured as AF0)
+    ///
+    /// NOTE this doesn't start the selected oscillator; e.g. the LSE must be enabled separately.
+    /// Also note that, unlike on other families, the LSE can't be used as the system clock on the
+    /// STM32F30x; this is the only way to route it outside of the backup domain
+    pub fn mco(mut self, source: Mco) -> Self {
+        self.mco = Some(source);
+        self
+    }
+
     /// Sets a frequency for the APB1 bus
     pub fn pclk1<F>(mut self, freq: F) -> Self
     where
@@ -141,7 +513,48 @@ impl CFGR {
     }
 
     /// Freezes the clock configuration, making it effective
-    pub fn freeze(self, acr: &mut ACR) -> Clocks {
+    ///
+    /// The HSI is left running in every configuration, as it's the source of the PLL, so
+    /// peripherals clocked from it keep working. NOTE this costs about 80 uA on top of the PLL.
+    ///
+    /// # Panics
+    ///
+    /// If a clock doesn't become ready in time; see `try_freeze`
+    pub fn freeze(mut self, acr: &mut ACR) -> Clocks {
+        self.reconfigure(acr)
+    }
+
+    /// Like `freeze` but returns an error, rather than panicking, if a clock doesn't become ready
+    /// in time
+    ///
+    /// NOTE on error the clock tree is left half way through the switch, e.g. running from the
+    /// HSI with the PLL off, and the flash wait states may be higher than needed.
+    pub fn try_freeze(mut self, acr: &mut ACR) -> Result<Clocks, ClockError> {
+        self.try_reconfigure(acr)
+    }
+
+    /// Makes the clock configuration effective without consuming it
+    ///
+    /// This can be called again, after changing the configuration, to switch the system clock at
+    /// runtime, e.g. between a fast profile for when there's work to do and a slow one for when
+    /// the device is idle. The system clock is temporarily switched to the HSI while the PLL is
+    /// reconfigured, and the flash wait states are raised before speeding up or lowered after
+    /// slowing down.
+    ///
+    /// NOTE the drivers that were created with the previous `Clocks` (`Serial`, `Spi`, `I2c`,
+    /// `Timer`, `Delay`, etc.) keep using the old frequencies, so their baud rates and timings will
+    /// be off. `free` them before switching and create them again with the returned `Clocks`.
+    ///
+    /// # Panics
+    ///
+    /// If a clock doesn't become ready in time; see `try_reconfigure`
+    pub fn reconfigure(&mut self, acr: &mut ACR) -> Clocks {
+        self.try_reconfigure(acr).unwrap()
+    }
+
+    /// Like `reconfigure` but returns an error, rather than panicking, if a clock doesn't become
+    /// ready in time (see `try_freeze`)
+    pub fn try_reconfigure(&mut self, acr: &mut ACR) -> Result<Clocks, ClockError> {
         let pllmul = (2 * self.sysclk.unwrap_or(HSI)) / HSI;
         let pllmul = cmp::min(cmp::max(pllmul, 2), 16);
         let pllmul_bits = if pllmul == 2 {
@@ -154,79 +567,69 @@ impl CFGR {
 
         assert!(sysclk <= 72_000_000);
 
-        let hpre_bits = self.hclk
-            .map(|hclk| match sysclk / hclk {
-                0 => unreachable!(),
-                1 => 0b0111,
-                2 => 0b1000,
-                3...5 => 0b1001,
-                6...11 => 0b1010,
-                12...39 => 0b1011,
-                40...95 => 0b1100,
-                96...191 => 0b1101,
-                192...383 => 0b1110,
-                _ => 0b1111,
-            })
-            .unwrap_or(0b0111);
-
-        let hclk = sysclk / (1 << (hpre_bits - 0b0111));
+        let hpre_bits = hpre_bits(sysclk, self.hclk.unwrap_or(sysclk));
+        let hclk = sysclk / hpre_div(hpre_bits);
 
         assert!(hclk <= 72_000_000);
 
-        let ppre1_bits = self.pclk1
-            .map(|pclk1| match hclk / pclk1 {
-                0 => unreachable!(),
-                1 => 0b011,
-                2 => 0b100,
-                3...5 => 0b101,
-                6...11 => 0b110,
-                _ => 0b111,
-            })
-            .unwrap_or(0b011);
-
-        let ppre1 = 1 << (ppre1_bits - 0b011);
+        let ppre1_bits = ppre_bits(hclk, self.pclk1.unwrap_or(cmp::min(hclk, 36_000_000)));
+        let ppre1 = ppre_div(ppre1_bits);
         let pclk1 = hclk / u32(ppre1);
 
         assert!(pclk1 <= 36_000_000);
 
-        let ppre2_bits = self.pclk2
-            .map(|pclk2| match hclk / pclk2 {
-                0 => unreachable!(),
-                1 => 0b011,
-                2 => 0b100,
-                3...5 => 0b101,
-                6...11 => 0b110,
-                _ => 0b111,
-            })
-            .unwrap_or(0b011);
-
-        let ppre2 = 1 << (ppre2_bits - 0b011);
+        let ppre2_bits = ppre_bits(hclk, self.pclk2.unwrap_or(hclk));
+        let ppre2 = ppre_div(ppre2_bits);
         let pclk2 = hclk / u32(ppre2);
 
         assert!(pclk2 <= 72_000_000);
 
-        // adjust flash wait states
-        unsafe {
-            acr.acr().write(|w| {
-                w.latency().bits(if sysclk <= 24_000_000 {
-                    0b000
-                } else if sysclk <= 48_000_000 {
-                    0b001
-                } else {
-                    0b010
-                })
-            })
+        let latency = if sysclk <= 24_000_000 {
+            0b000
+        } else if sysclk <= 48_000_000 {
+            0b001
+        } else {
+            0b010
+        };
+
+        // adjust flash wait states *before* speeding up
+        if latency > acr.acr().read().latency().bits() {
+            acr.acr().modify(|_, w| unsafe { w.latency().bits(latency) });
         }
 
+        let mco_bits = self.mco.map(|mco| mco.bits()).unwrap_or(0b000);
+
         let rcc = unsafe { &*RCC::ptr() };
+
+        // the PLL can't be reconfigured while it's in use so run from the HSI in the meantime
+        if sysclk_source() != SysClkSource::Hsi {
+            rcc.cfgr.modify(|_, w| unsafe { w.sw().bits(0b00) });
+            wait_clock(Clock::Hsi, || sysclk_source() == SysClkSource::Hsi)?;
+        }
+
+        rcc.cr.modify(|_, w| w.pllon().clear_bit());
+        wait_clock(Clock::Pll, || rcc.cr.read().pllrdy().bit_is_clear())?;
+
         if let Some(pllmul_bits) = pllmul_bits {
             // use PLL as source
 
-            rcc.cfgr.write(|w| unsafe { w.pllmul().bits(pllmul_bits) });
+            // PLLSRC: HSI / 2
+            // USBPRE: the PLL clock is only usable by the USB if it's 48 MHz (undivided) or 72
+            // MHz (divided by 1.5)
+            rcc.cfgr.modify(|_, w| unsafe {
+                w.pllmul()
+                    .bits(pllmul_bits)
+                    .pllsrc()
+                    .clear_bit()
+                    .usbpres()
+                    .bit(sysclk == 48_000_000)
+            });
 
-            rcc.cr.write(|w| w.pllon().set_bit());
+            // the HSI feeds the PLL so it stays on; peripherals clocked from the HSI, e.g. the I2C
+            // buses, keep working after the switch
+            rcc.cr.modify(|_, w| w.hsion().set_bit().pllon().set_bit());
 
-            while rcc.cr.read().pllrdy().bit_is_clear() {}
+            wait_clock(Clock::Pll, || rcc.cr.read().pllrdy().bit_is_set())?;
 
             // SW: PLL selected as system clock
             rcc.cfgr.modify(|_, w| unsafe {
@@ -236,9 +639,13 @@ impl CFGR {
                     .bits(ppre1_bits)
                     .hpre()
                     .bits(hpre_bits)
+                    .mco()
+                    .bits(mco_bits)
                     .sw()
                     .bits(0b10)
             });
+
+            wait_clock(Clock::Pll, || sysclk_source() == SysClkSource::Pll)?;
         } else {
             // use HSI as source
 
@@ -250,38 +657,217 @@ impl CFGR {
                     .bits(ppre1_bits)
                     .hpre()
                     .bits(hpre_bits)
+                    .mco()
+                    .bits(mco_bits)
                     .sw()
                     .bits(0b00)
             });
         }
 
-        Clocks {
+        if let Some(source) = self.i2c1 {
+            rcc.cfgr3.modify(|_, w| w.i2c1sw().bit(source == I2cClock::Sysclk));
+        }
+
+        if let Some(source) = self.i2c2 {
+            rcc.cfgr3.modify(|_, w| w.i2c2sw().bit(source == I2cClock::Sysclk));
+        }
+
+        // adjust flash wait states *after* slowing down
+        if latency < acr.acr().read().latency().bits() {
+            acr.acr().modify(|_, w| unsafe { w.latency().bits(latency) });
+        }
+
+        let usbclk = pllmul_bits.map(|_| Hertz(usbclk(sysclk, sysclk == 48_000_000)));
+
+        Ok(Clocks {
             hclk: Hertz(hclk),
             pclk1: Hertz(pclk1),
             pclk2: Hertz(pclk2),
             ppre1,
             ppre2,
             sysclk: Hertz(sysclk),
-        }
+            usbclk,
+        }.publish())
+    }
+}
+
+/// Returns the USB clock derived from a PLL running at `pllclk`; USBPRE = 1 (`undivided`) passes
+/// it through, USBPRE = 0 divides it by 1.5
+fn usbclk(pllclk: u32, undivided: bool) -> u32 {
+    if undivided {
+        pllclk
+    } else {
+        pllclk * 2 / 3
+    }
+}
+
+/// Returns the HPRE bits that divide `sysclk` down to a frequency that doesn't exceed `hclk`
+fn hpre_bits(sysclk: u32, hclk: u32) -> u8 {
+    // NOTE round up the ratio so that the resulting frequency is never above the requested one
+    match (sysclk + hclk - 1) / hclk {
+        0...1 => 0b0111,
+        2 => 0b1000,
+        3...4 => 0b1001,
+        5...8 => 0b1010,
+        9...16 => 0b1011,
+        // NOTE there's no /32 prescaler
+        17...64 => 0b1100,
+        65...128 => 0b1101,
+        129...256 => 0b1110,
+        _ => 0b1111,
     }
 }
 
+/// Returns the division factor selected by the HPRE `bits`
+fn hpre_div(bits: u8) -> u32 {
+    match bits {
+        0b1000 => 2,
+        0b1001 => 4,
+        0b1010 => 8,
+        0b1011 => 16,
+        0b1100 => 64,
+        0b1101 => 128,
+        0b1110 => 256,
+        0b1111 => 512,
+        _ => 1,
+    }
+}
+
+/// Returns the PPREx bits that divide `hclk` down to a frequency that doesn't exceed `pclk`
+fn ppre_bits(hclk: u32, pclk: u32) -> u8 {
+    // NOTE round up the ratio so that the resulting frequency is never above the requested one
+    match (hclk + pclk - 1) / pclk {
+        0...1 => 0b011,
+        2 => 0b100,
+        3...4 => 0b101,
+        5...8 => 0b110,
+        _ => 0b111,
+    }
+}
+
+/// Returns the division factor selected by the PPREx `bits`
+fn ppre_div(bits: u8) -> u8 {
+    match bits {
+        0b100 => 2,
+        0b101 => 4,
+        0b110 => 8,
+        0b111 => 16,
+        _ => 1,
+    }
+}
+
+#[cfg(feature = "global-clocks")]
+static CLOCKS: Mutex<Cell<Option<Clocks>>> = Mutex::new(Cell::new(None));
+
 /// Frozen clock frequencies
 ///
-/// The existence of this value indicates that the clock configuration can no longer be changed
+/// A snapshot of the clock configuration set by `freeze` or `reconfigure`. `CFGR::reconfigure`
+/// can change the clock tree later on; the `Clocks` values obtained before that are then stale
+/// and the drivers created with them run with the wrong timings.
 #[derive(Clone, Copy)]
 pub struct Clocks {
     hclk: Hertz,
     pclk1: Hertz,
     pclk2: Hertz,
     ppre1: u8,
-    // TODO remove `allow`
-    #[allow(dead_code)]
     ppre2: u8,
     sysclk: Hertz,
+    usbclk: Option<Hertz>,
+}
+
+impl fmt::Debug for Clocks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            concat!(
+                "Clocks {{ sysclk: {} Hz, hclk: {} Hz, pclk1: {} Hz, pclk2: {} Hz, ",
+                "ppre1: {}, ppre2: {} }}"
+            ),
+            self.sysclk.0, self.hclk.0, self.pclk1.0, self.pclk2.0, self.ppre1, self.ppre2,
+        )
+    }
+}
+
+/// Reason why the clock tree can't clock the USB peripheral
+#[derive(Debug, PartialEq)]
+pub enum UsbClockError {
+    /// The system clock doesn't come from the PLL, which is the only USB clock source
+    PllNotUsed,
+    /// The USB clock isn't 48 MHz; holds its actual frequency, in Hz
+    WrongFrequency(u32),
+    #[doc(hidden)]
+    _Extensible,
 }
 
 impl Clocks {
+    /// Reconstructs the clock frequencies from the current state of the RCC registers
+    ///
+    /// This is meant for applications that inherit an already configured clock tree (e.g. from a
+    /// bootloader or after a soft reset) and don't want to reconfigure it with `CFGR.freeze`.
+    ///
+    /// Returns `None` if the clock tree is in a state that can't be described here, i.e. if the
+    /// system clock (directly or through the PLL) comes from the HSE, whose frequency is unknown,
+    /// or if the PLL is selected but not locked
+    pub fn from_hardware(_cfgr: CFGR) -> Option<Clocks> {
+        // NOTE(unsafe) read only access; `_cfgr` ensures the configuration can't be changed
+        let rcc = unsafe { &*RCC::ptr() };
+        let cfgr = rcc.cfgr.read();
+
+        let mut usb = None;
+        let sysclk = match sysclk_source() {
+            SysClkSource::Hsi => HSI,
+            SysClkSource::Pll => {
+                if cfgr.pllsrc().bit_is_set() || rcc.cr.read().pllrdy().bit_is_clear() {
+                    return None;
+                }
+
+                // PLLMUL: 0b0000 = x2 ... 0b1110 = x16; 0b1111 is also x16
+                let pllmul = cmp::min(u32(cfgr.pllmul().bits()) + 2, 16);
+                let pllclk = pllmul * HSI / 2;
+                usb = Some(Hertz(usbclk(pllclk, cfgr.usbpres().bit_is_set())));
+
+                pllclk
+            }
+            SysClkSource::Hse => return None,
+        };
+
+        let hclk = sysclk / hpre_div(cfgr.hpre().bits());
+
+        let ppre1 = ppre_div(cfgr.ppre1().bits());
+        let ppre2 = ppre_div(cfgr.ppre2().bits());
+
+        Some(
+            Clocks {
+                hclk: Hertz(hclk),
+                pclk1: Hertz(hclk / u32(ppre1)),
+                pclk2: Hertz(hclk / u32(ppre2)),
+                ppre1,
+                ppre2,
+                sysclk: Hertz(sysclk),
+                usbclk: usb,
+            }.publish(),
+        )
+    }
+
+    /// Returns the clock frequencies that are currently in effect
+    ///
+    /// This is an alternative to passing `Clocks` around for applications that use a single clock
+    /// configuration. It returns `None` until `CFGR.freeze` (or `CFGR.reconfigure` or
+    /// `Clocks::from_hardware`) has been called, so drivers must not be created before the clocks
+    /// are configured.
+    #[cfg(feature = "global-clocks")]
+    pub fn get() -> Option<Clocks> {
+        interrupt::free(|cs| CLOCKS.borrow(cs).get())
+    }
+
+    /// Makes this configuration the one returned by `Clocks::get`
+    fn publish(self) -> Self {
+        #[cfg(feature = "global-clocks")]
+        interrupt::free(|cs| CLOCKS.borrow(cs).set(Some(self)));
+
+        self
+    }
+
     /// Returns the frequency of the AHB
     pub fn hclk(&self) -> Hertz {
         self.hclk
@@ -301,8 +887,6 @@ impl Clocks {
         self.ppre1
     }
 
-    // TODO remove `allow`
-    #[allow(dead_code)]
     pub(crate) fn ppre2(&self) -> u8 {
         self.ppre2
     }
@@ -311,4 +895,168 @@ impl Clocks {
     pub fn sysclk(&self) -> Hertz {
         self.sysclk
     }
+
+    /// Returns `true` if the system clock comes from the PLL
+    pub fn is_pll_used(&self) -> bool {
+        self.usbclk.is_some()
+    }
+
+    /// Checks that the USB peripheral gets the 48 MHz clock it needs
+    ///
+    /// `freeze` sets the USB prescaler so a `sysclk` of 48 MHz passes this check. NOTE as the PLL
+    /// is fed from the HSI here, the clock may still be too inaccurate for some hosts; USB
+    /// formally requires a crystal.
+    pub fn check_usb_clock(&self) -> Result<(), UsbClockError> {
+        match self.usbclk {
+            None => Err(UsbClockError::PllNotUsed),
+            Some(Hertz(48_000_000)) => Ok(()),
+            Some(Hertz(usbclk)) => Err(UsbClockError::WrongFrequency(usbclk)),
+        }
+    }
+
+    /// Returns `true` if the USB peripheral gets the 48 MHz clock it needs (see
+    /// `check_usb_clock`)
+    pub fn usb_clock_valid(&self) -> bool {
+        self.check_usb_clock().is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cmp;
+
+    use super::{
+        hpre_bits, hpre_div, ppre_bits, ppre_div, usbclk, wait_clock, wait_ready, Clock, ClockError,
+        Clocks, Timeout, UsbClockError, HSI, READY_RETRIES,
+    };
+    use time::Hertz;
+
+    #[test]
+    fn hpre_boundaries() {
+        let sysclk = 64_000_000;
+
+        assert_eq!(hpre_bits(sysclk, sysclk), 0b0111);
+        assert_eq!(hpre_bits(sysclk, sysclk / 2), 0b1000);
+        assert_eq!(hpre_bits(sysclk, sysclk / 3), 0b1001);
+        assert_eq!(hpre_bits(sysclk, sysclk / 32), 0b1100);
+        assert_eq!(hpre_bits(sysclk, sysclk / 512), 0b1111);
+        assert_eq!(hpre_bits(sysclk, 1), 0b1111);
+
+        // requesting a frequency above `sysclk` leaves the AHB undivided
+        assert_eq!(hpre_bits(sysclk, 2 * sysclk), 0b0111);
+    }
+
+    #[test]
+    fn hpre_div_matches_bits() {
+        let divs = [1, 2, 4, 8, 16, 64, 128, 256, 512];
+
+        for (bits, div) in (0b0111..0b10000).zip(divs.iter()) {
+            assert_eq!(hpre_div(bits), *div);
+        }
+    }
+
+    #[test]
+    fn ppre_boundaries() {
+        let hclk = 72_000_000;
+
+        assert_eq!(ppre_bits(hclk, hclk), 0b011);
+        assert_eq!(ppre_bits(hclk, hclk / 2), 0b100);
+        assert_eq!(ppre_bits(hclk, hclk / 3), 0b101);
+        assert_eq!(ppre_bits(hclk, hclk / 16), 0b111);
+        assert_eq!(ppre_bits(hclk, hclk / 512), 0b111);
+
+        for (bits, div) in (0b011..0b1000).zip([1, 2, 4, 8, 16].iter()) {
+            assert_eq!(ppre_div(bits), *div);
+        }
+    }
+
+    #[test]
+    fn never_above_requested() {
+        // every SYSCLK reachable through the HSI / 2 PLL input
+        for pllmul in 2..17 {
+            let sysclk = pllmul * HSI / 2;
+
+            for req_hclk in (1..73).map(|f| f * 1_000_000) {
+                let hclk = sysclk / hpre_div(hpre_bits(sysclk, req_hclk));
+
+                assert!(hclk <= req_hclk || req_hclk < sysclk / 512);
+
+                for req_pclk in (1..37).map(|f| f * 1_000_000) {
+                    let pclk = hclk / u32::from(ppre_div(ppre_bits(hclk, req_pclk)));
+
+                    assert!(pclk <= req_pclk || req_pclk < hclk / 16);
+                }
+
+                // default APB1 prescaler
+                let pclk1 = hclk / u32::from(ppre_div(ppre_bits(hclk, cmp::min(hclk, 36_000_000))));
+
+                assert!(pclk1 <= 36_000_000);
+            }
+        }
+    }
+
+    #[test]
+    fn wait_ready_is_bounded() {
+        let mut polls = 0;
+        assert_eq!(
+            wait_ready(
+                || {
+                    polls += 1;
+                    polls == 3
+                },
+                10
+            ),
+            Ok(())
+        );
+        assert_eq!(polls, 3);
+
+        polls = 0;
+        assert_eq!(
+            wait_ready(
+                || {
+                    polls += 1;
+                    false
+                },
+                10
+            ),
+            Err(Timeout)
+        );
+        assert_eq!(polls, 10);
+
+        polls = 0;
+        match wait_clock(Clock::Hsi, || {
+            polls += 1;
+            false
+        }) {
+            Err(ClockError::Timeout(Clock::Hsi)) => {}
+            _ => panic!(),
+        }
+        assert_eq!(polls, READY_RETRIES);
+    }
+
+    #[test]
+    fn usb_clock() {
+        assert_eq!(usbclk(48_000_000, true), 48_000_000);
+        assert_eq!(usbclk(72_000_000, false), 48_000_000);
+
+        let clocks = |usbclk| Clocks {
+            hclk: Hertz(HSI),
+            pclk1: Hertz(HSI),
+            pclk2: Hertz(HSI),
+            ppre1: 1,
+            ppre2: 1,
+            sysclk: Hertz(HSI),
+            usbclk,
+        };
+
+        assert!(clocks(Some(Hertz(48_000_000))).usb_clock_valid());
+        assert_eq!(
+            clocks(None).check_usb_clock(),
+            Err(UsbClockError::PllNotUsed)
+        );
+        assert_eq!(
+            clocks(Some(Hertz(42_666_666))).check_usb_clock(),
+            Err(UsbClockError::WrongFrequency(42_666_666))
+        );
+    }
 }
@@ -0,0 +1,115 @@
+//! Cooperative scheduling of periodic tasks from a single timer
+//!
+//! A `Scheduler` counts the time outs of a `CountDown` timer and reports which of up to 32 tasks,
+//! each with its own period (in timer ticks), are due. Running the tasks is left to the user, e.g.
+//! from the main loop or directly from the timer interrupt handler.
+
+use hal::timer::CountDown;
+
+/// Maximum number of tasks a `Scheduler` can track
+pub const MAX_TASKS: usize = 32;
+
+/// Periodic task scheduler
+pub struct Scheduler<'a, TIM> {
+    timer: TIM,
+    periods: &'a [u32],
+    elapsed: [u32; MAX_TASKS],
+}
+
+impl<'a, TIM> Scheduler<'a, TIM>
+where
+    TIM: CountDown,
+{
+    /// Tracks one task per element of `periods`, each one being due every `periods[i]` ticks of
+    /// `timer`
+    ///
+    /// # Panics
+    ///
+    /// If there are more than `MAX_TASKS` periods or if a period is zero
+    pub fn new(timer: TIM, periods: &'a [u32]) -> Self {
+        assert!(periods.len() <= MAX_TASKS);
+        assert!(periods.iter().all(|period| *period != 0));
+
+        Scheduler {
+            timer,
+            periods,
+            elapsed: [0; MAX_TASKS],
+        }
+    }
+
+    /// Accounts for a timer tick and returns a bitmask of the tasks that are due
+    ///
+    /// Bit `i` is set if task `i` is due. Returns 0 if the timer hasn't timed out yet so this can
+    /// be polled, or called from the timer interrupt handler.
+    pub fn tick(&mut self) -> u32 {
+        if self.timer.wait().is_err() {
+            return 0;
+        }
+
+        let mut due = 0;
+        for (i, (period, elapsed)) in self.periods.iter().zip(self.elapsed.iter_mut()).enumerate() {
+            *elapsed += 1;
+
+            if *elapsed >= *period {
+                *elapsed = 0;
+                due |= 1 << i;
+            }
+        }
+
+        due
+    }
+
+    /// Releases the timer
+    pub fn free(self) -> TIM {
+        self.timer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hal::timer::CountDown;
+    use nb;
+    use void::Void;
+
+    use super::Scheduler;
+
+    /// Timer that times out on every other `wait`
+    struct Timer {
+        ready: bool,
+    }
+
+    impl CountDown for Timer {
+        type Time = ();
+
+        fn start<T>(&mut self, _: T)
+        where
+            T: Into<()>,
+        {
+        }
+
+        fn wait(&mut self) -> nb::Result<(), Void> {
+            self.ready = !self.ready;
+
+            if self.ready {
+                Ok(())
+            } else {
+                Err(nb::Error::WouldBlock)
+            }
+        }
+    }
+
+    #[test]
+    fn due_tasks() {
+        let periods = [1, 2, 3];
+        let mut scheduler = Scheduler::new(Timer { ready: false }, &periods);
+
+        let mut due = [0; 6];
+        for mask in due.iter_mut() {
+            *mask = scheduler.tick();
+            // not timed out
+            assert_eq!(scheduler.tick(), 0);
+        }
+
+        assert_eq!(due, [0b001, 0b011, 0b101, 0b011, 0b001, 0b111]);
+    }
+}
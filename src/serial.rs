@@ -1,21 +1,23 @@
 //! Serial
 
 use core::marker::PhantomData;
-use core::ptr;
+use core::{ptr, str};
 
+use cast::{u16, u32, u8};
 use hal::serial;
+use hal::spi::{FullDuplex, Mode, Phase, Polarity};
 use nb;
-use stm32f30x::{USART1, USART2, USART3};
+use stm32f30x::{Interrupt, USART1, USART2, USART3};
 use void::Void;
 
-use gpio::gpioa::{PA10, PA2, PA3, PA9};
-use gpio::gpiob::{PB10, PB11, PB6, PB7};
-use gpio::gpioc::{PC10, PC11, PC4, PC5};
-use gpio::gpiod::{PD5, PD6, PD8, PD9};
+use gpio::gpioa::{PA10, PA2, PA3, PA4, PA8, PA9};
+use gpio::gpiob::{PB10, PB11, PB12, PB5, PB6, PB7};
+use gpio::gpioc::{PC10, PC11, PC12, PC4, PC5};
+use gpio::gpiod::{PD10, PD5, PD6, PD7, PD8, PD9};
 use gpio::gpioe::{PE0, PE1, PE15};
-use gpio::AF7;
+use gpio::{gpioa, gpiob, gpioc, gpiod, gpioe, AF7};
 use rcc::{APB1, APB2, Clocks};
-use time::Bps;
+use time::{Bps, Hertz};
 
 /// Interrupt event
 pub enum Event {
@@ -23,6 +25,61 @@ pub enum Event {
     Rxne,
     /// New data can be sent
     Txe,
+    /// The receiver timed out (see `Serial::set_receiver_timeout`)
+    ReceiverTimeout,
+}
+
+flags! {
+    /// USART status flags (ISR register)
+    pub struct Flags {
+        /// Parity error
+        const PE = 0;
+        /// Framing error
+        const FE = 1;
+        /// Noise detected
+        const NF = 2;
+        /// Overrun error
+        const ORE = 3;
+        /// The RX line went idle
+        const IDLE = 4;
+        /// A received byte can be read
+        const RXNE = 5;
+        /// Transmission complete
+        const TC = 6;
+        /// New data can be sent
+        const TXE = 7;
+        /// The CTS input changed
+        const CTSIF = 9;
+        /// The receiver timed out
+        const RTOF = 11;
+        /// A byte is being received
+        const BUSY = 16;
+    }
+}
+
+impl Flags {
+    /// Flags that are cleared through the ICR register; the others are cleared by reading or
+    /// writing data, or by the hardware
+    fn clearable() -> Self {
+        Flags::PE
+            | Flags::FE
+            | Flags::NF
+            | Flags::ORE
+            | Flags::IDLE
+            | Flags::TC
+            | Flags::CTSIF
+            | Flags::RTOF
+    }
+}
+
+/// IrDA SIR encoder mode
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IrdaMode {
+    /// Pulses last 3/16 of a bit time
+    Normal,
+    /// Pulses last 3 periods of a ~1.8432 MHz clock derived from the USART clock, i.e. ~1.63 us
+    /// whatever the baud rate, which saves power on the IR LED
+    LowPower,
 }
 
 /// Serial error
@@ -47,6 +104,19 @@ pub unsafe trait TxPin<USART> {}
 /// RX pin - DO NOT IMPLEMENT THIS TRAIT
 pub unsafe trait RxPin<USART> {}
 
+/// CK pin - DO NOT IMPLEMENT THIS TRAIT
+pub unsafe trait CkPin<USART> {}
+
+unsafe impl CkPin<USART1> for PA8<AF7> {}
+
+unsafe impl CkPin<USART2> for PA4<AF7> {}
+unsafe impl CkPin<USART2> for PB5<AF7> {}
+unsafe impl CkPin<USART2> for PD7<AF7> {}
+
+unsafe impl CkPin<USART3> for PB12<AF7> {}
+unsafe impl CkPin<USART3> for PC12<AF7> {}
+unsafe impl CkPin<USART3> for PD10<AF7> {}
+
 unsafe impl TxPin<USART1> for PA9<AF7> {}
 unsafe impl TxPin<USART1> for PB6<AF7> {}
 unsafe impl TxPin<USART1> for PC4<AF7> {}
@@ -76,12 +146,105 @@ unsafe impl RxPin<USART3> for PC11<AF7> {}
 unsafe impl RxPin<USART3> for PD9<AF7> {}
 unsafe impl RxPin<USART3> for PE15<AF7> {}
 
+/// TX pin that can drive an IrDA transceiver -- DO NOT IMPLEMENT THIS TRAIT
+pub unsafe trait IrdaTxPin<USART>: TxPin<USART> {
+    /// OTYPER register of the port the pin belongs to
+    type Otyper;
+
+    #[doc(hidden)]
+    fn set_open_drain(&mut self, otyper: &mut Self::Otyper);
+}
+
+macro_rules! irda_tx_pins {
+    ($($USARTX:ident: $gpiox:ident::$PXi:ident,)+) => {
+        $(
+            unsafe impl IrdaTxPin<$USARTX> for $PXi<AF7> {
+                type Otyper = $gpiox::OTYPER;
+
+                fn set_open_drain(&mut self, otyper: &mut $gpiox::OTYPER) {
+                    $PXi::set_open_drain(self, otyper, true)
+                }
+            }
+        )+
+    }
+}
+
+irda_tx_pins! {
+    USART1: gpioa::PA9,
+    USART1: gpiob::PB6,
+    USART1: gpioc::PC4,
+    USART1: gpioe::PE0,
+    USART2: gpioa::PA2,
+    USART2: gpiod::PD5,
+    USART3: gpiob::PB10,
+    USART3: gpioc::PC10,
+    USART3: gpiod::PD8,
+}
+
+/// Computes the BRR register value for a USART clocked at `clk` to operate at `baud` bits per
+/// second
+///
+/// `over8` selects oversampling by 8 (`CR1.OVER8 = 1`) instead of the default oversampling by 16
+///
+/// # Panics
+///
+/// If `baud` is higher than `clk` / 16 (`clk` / 8 with `over8`), or so low that the divider
+/// doesn't fit in the 16-bit register
+pub fn brr(clk: Hertz, baud: u32, over8: bool) -> u16 {
+    let brr = if over8 {
+        // BRR[2:0] = USARTDIV[3:0] >> 1; BRR[3] must be kept cleared
+        let usartdiv = 2 * clk.0 / baud;
+        assert!(usartdiv >= 16, "impossible baud rate");
+        (usartdiv & !0xf) | ((usartdiv & 0xf) >> 1)
+    } else {
+        let usartdiv = clk.0 / baud;
+        assert!(usartdiv >= 16, "impossible baud rate");
+        usartdiv
+    };
+
+    assert!(brr <= 0xffff, "baud rate too low for the USART clock");
+    u16(brr).unwrap()
+}
+
+/// `LineReader` error
+#[derive(Debug)]
+pub enum LineError<E> {
+    /// The underlying reader failed
+    Read(E),
+    /// The line didn't fit in the buffer; the rest of it is dropped
+    Overflow,
+    /// The line is not valid UTF-8
+    Utf8,
+    #[doc(hidden)]
+    _Extensible,
+}
+
+/// Assembles the bytes coming out of a serial receiver into lines
+///
+/// Lines end with `\n`; a `\r` right before it is dropped as well, so both Unix and DOS line
+/// endings work.
+pub struct LineReader<'a, RX> {
+    rx: RX,
+    buffer: &'a mut [u8],
+    len: usize,
+    discard: bool,
+}
+
 /// Serial abstraction
 pub struct Serial<USART, PINS> {
     usart: USART,
     pins: PINS,
 }
 
+/// USART in synchronous mode, operating as a SPI master
+///
+/// The USART drives its CK pin as SCK, TX as MOSI and samples RX as MISO, so it can stand in
+/// for a SPI peripheral that's already taken. There's no chip select; drive one with a GPIO.
+pub struct SyncSerial<USART, PINS> {
+    usart: USART,
+    pins: PINS,
+}
+
 /// Serial receiver
 pub struct Rx<USART> {
     _usart: PhantomData<USART>,
@@ -94,7 +257,15 @@ pub struct Tx<USART> {
 
 macro_rules! hal {
     ($(
-        $USARTX:ident: ($usartX:ident, $APB:ident, $usartXen:ident, $usartXrst:ident, $pclkX:ident),
+        $USARTX:ident: (
+            $usartX:ident,
+            $usartX_irda:ident,
+            $APB:ident,
+            $usartXen:ident,
+            $usartXrst:ident,
+            $pclkX:ident,
+            $INTERRUPT:ident
+        ),
     )+) => {
         $(
             impl<TX, RX> Serial<$USARTX, (TX, RX)> {
@@ -119,9 +290,8 @@ macro_rules! hal {
                     // TODO enable DMA
                     // usart.cr3.write(|w| w.rtse().clear_bit().ctse().clear_bit());
 
-                    let brr = clocks.$pclkX().0 / baud_rate.0;
-                    assert!(brr >= 16, "impossible baud rate");
-                    usart.brr.write(|w| unsafe { w.bits(brr) });
+                    let brr = brr(clocks.$pclkX(), baud_rate.0, false);
+                    usart.brr.write(|w| unsafe { w.bits(u32(brr)) });
 
                     // UE: enable USART
                     // RE: enable receiver
@@ -133,6 +303,35 @@ macro_rules! hal {
                     Serial { usart, pins }
                 }
 
+                /// Configures a USART peripheral as an IrDA SIR link
+                ///
+                /// The TX pin is switched to an open drain output driver, which is what the usual
+                /// IrDA transceivers and IR LED drivers expect, and the SIR encoder / decoder is
+                /// enabled in `mode` (see `enable_irda`). In normal mode the pulses last a fixed
+                /// 3/16 of a bit time. IrDA SIR links run at 115200 bps at most.
+                pub fn $usartX_irda(
+                    usart: $USARTX,
+                    pins: (TX, RX),
+                    otyper: &mut TX::Otyper,
+                    baud_rate: Bps,
+                    mode: IrdaMode,
+                    clocks: Clocks,
+                    apb: &mut $APB,
+                ) -> Self
+                where
+                    TX: IrdaTxPin<$USARTX>,
+                    RX: RxPin<$USARTX>,
+                {
+                    assert!(baud_rate.0 <= 115_200, "IrDA SIR runs at 115200 bps at most");
+
+                    let (mut tx, rx) = pins;
+                    tx.set_open_drain(otyper);
+
+                    let mut serial = Serial::$usartX(usart, (tx, rx), baud_rate, clocks, apb);
+                    serial.enable_irda(mode, &clocks);
+                    serial
+                }
+
                 /// Starts listening for an interrupt event
                 pub fn listen(&mut self, event: Event) {
                     match event {
@@ -142,6 +341,9 @@ macro_rules! hal {
                         Event::Txe => {
                             self.usart.cr1.modify(|_, w| w.txeie().set_bit())
                         },
+                        Event::ReceiverTimeout => {
+                            self.usart.cr1.modify(|_, w| w.rtoie().set_bit())
+                        },
                     }
                 }
 
@@ -154,6 +356,9 @@ macro_rules! hal {
                         Event::Txe => {
                             self.usart.cr1.modify(|_, w| w.txeie().clear_bit())
                         },
+                        Event::ReceiverTimeout => {
+                            self.usart.cr1.modify(|_, w| w.rtoie().clear_bit())
+                        },
                     }
                 }
 
@@ -169,12 +374,262 @@ macro_rules! hal {
                     )
                 }
 
+                /// Swaps the functions of the TX and RX pins
+                ///
+                /// Useful for boards where the lines are wired the wrong way around; the type of
+                /// the pins passed to the constructor doesn't change. The USART is briefly disabled
+                /// to apply the change, as the setting can only be changed while it's disabled;
+                /// any ongoing transmission is completed first.
+                pub fn set_swap(&mut self, swap: bool) {
+                    self.reconfigure(|usart| usart.cr2.modify(|_, w| w.swap().bit(swap)));
+                }
+
+                /// Inverts the levels of the RX line, of the TX line and / or of the data bits
+                ///
+                /// Inverting the `rx` / `tx` lines makes them idle low. Inverting the `data` bits
+                /// sends and receives 1s as low levels and 0s as high levels. Like `set_swap`, this
+                /// briefly disables the USART.
+                pub fn set_inversion(&mut self, rx: bool, tx: bool, data: bool) {
+                    self.reconfigure(|usart| {
+                        usart
+                            .cr2
+                            .modify(|_, w| w.rxinv().bit(rx).txinv().bit(tx).datainv().bit(data))
+                    });
+                }
+
+                /// Enables the receiver timeout
+                ///
+                /// The timeout flag (see `Rx::is_timed_out`) is raised when the RX line stays idle
+                /// for `bits` bit times (up to 2^24 - 1) after the last received character, e.g.
+                /// 35 for the 3.5 character gap that ends a Modbus RTU frame. Like `set_swap`, this
+                /// briefly disables the USART.
+                pub fn set_receiver_timeout(&mut self, bits: u32) {
+                    assert!(bits < (1 << 24));
+
+                    self.usart.rtor.modify(|_, w| unsafe { w.rto().bits(bits) });
+                    self.reconfigure(|usart| usart.cr2.modify(|_, w| w.rtoen().set_bit()));
+                }
+
+                /// Disables the receiver timeout
+                pub fn disable_receiver_timeout(&mut self) {
+                    self.reconfigure(|usart| usart.cr2.modify(|_, w| w.rtoen().clear_bit()));
+                }
+
+                /// Enables the IrDA SIR encoder / decoder
+                ///
+                /// The TX pin then idles low and outputs a short high pulse for every 0 bit, and
+                /// RX expects the same pulses, so the pins can be wired straight to an IrDA
+                /// transceiver. IrDA SIR links run at 115200 bps at most, with 8 data bits and 1
+                /// stop bit. Like `set_swap`, this briefly disables the USART.
+                pub fn enable_irda(&mut self, mode: IrdaMode, clocks: &Clocks) {
+                    let psc = match mode {
+                        // PSC must be 1 in normal mode
+                        IrdaMode::Normal => 1,
+                        IrdaMode::LowPower => {
+                            let psc = (clocks.$pclkX().0 + 921_600) / 1_843_200;
+                            assert!(psc != 0);
+                            u8(psc).unwrap()
+                        },
+                    };
+
+                    // NOTE GTPR can only be written to while the USART is disabled
+                    self.reconfigure(|usart| {
+                        usart.gtpr.modify(|_, w| unsafe { w.psc().bits(psc) });
+                        usart.cr3.modify(|_, w| {
+                            w.irlp().bit(mode == IrdaMode::LowPower).iren().set_bit()
+                        })
+                    });
+                }
+
+                /// Disables the IrDA SIR encoder / decoder
+                pub fn disable_irda(&mut self) {
+                    self.reconfigure(|usart| usart.cr3.modify(|_, w| w.iren().clear_bit()));
+                }
+
+                /// Returns the status flags
+                pub fn flags(&self) -> Flags {
+                    Flags::from_bits_truncate(self.usart.isr.read().bits())
+                }
+
+                /// Clears `flags`
+                ///
+                /// `RXNE`, `TXE` and `BUSY` can't be cleared this way: they follow the data
+                /// registers and the line, and are ignored
+                pub fn clear_flags(&mut self, flags: Flags) {
+                    // ICR bits sit at the same positions as the ISR flags they clear
+                    let bits = (flags & Flags::clearable()).bits();
+                    self.usart.icr.write(|w| unsafe { w.bits(bits) });
+                }
+
+                /// Runs `f` while the USART is disabled, waiting for any ongoing transmission
+                /// to complete first
+                fn reconfigure<F>(&mut self, f: F)
+                where
+                    F: FnOnce(&$USARTX),
+                {
+                    if self.usart.cr1.read().te().bit_is_set() {
+                        while self.usart.isr.read().tc().bit_is_clear() {}
+                    }
+
+                    self.usart.cr1.modify(|_, w| w.ue().clear_bit());
+                    f(&self.usart);
+                    self.usart.cr1.modify(|_, w| w.ue().set_bit());
+                }
+
+                /// Returns the interrupt this USART raises
+                pub fn interrupt(&self) -> Interrupt {
+                    Interrupt::$INTERRUPT
+                }
+
                 /// Releases the USART peripheral and associated pins
                 pub fn free(self) -> ($USARTX, (TX, RX)) {
                     (self.usart, self.pins)
                 }
             }
 
+            impl<CK, TX, RX> SyncSerial<$USARTX, (CK, TX, RX)> {
+                /// Configures a USART peripheral as a SPI master clocked at `freq`
+                ///
+                /// `mode` has the usual SPI meaning: the clock idles low or high (CPOL) and data
+                /// is captured on the first or second clock edge (CPHA). Data is sent MSB first
+                /// and a clock pulse is output for every bit, including the last one (LBCL), as
+                /// SPI slaves expect. `freq` can't be higher than 1 / 16 of the USART clock.
+                pub fn $usartX<F>(
+                    usart: $USARTX,
+                    pins: (CK, TX, RX),
+                    mode: Mode,
+                    freq: F,
+                    clocks: Clocks,
+                    apb: &mut $APB,
+                ) -> Self
+                where
+                    F: Into<Hertz>,
+                    CK: CkPin<$USARTX>,
+                    TX: TxPin<$USARTX>,
+                    RX: RxPin<$USARTX>,
+                {
+                    // enable or reset $USARTX
+                    apb.enr().modify(|_, w| w.$usartXen().enabled());
+                    apb.rstr().modify(|_, w| w.$usartXrst().set_bit());
+                    apb.rstr().modify(|_, w| w.$usartXrst().clear_bit());
+
+                    let brr = brr(clocks.$pclkX(), freq.into().0, false);
+                    usart.brr.write(|w| unsafe { w.bits(u32(brr)) });
+
+                    // NOTE CR2 can only be written to while the USART is disabled
+                    usart.cr2.write(|w| {
+                        w.clken()
+                            .set_bit()
+                            .cpol()
+                            .bit(mode.polarity == Polarity::IdleHigh)
+                            .cpha()
+                            .bit(mode.phase == Phase::CaptureOnSecondTransition)
+                            .lbcl()
+                            .set_bit()
+                            .msbfirst()
+                            .set_bit()
+                    });
+
+                    // UE: enable USART
+                    // RE: enable receiver
+                    // TE: enable transceiver
+                    usart
+                        .cr1
+                        .write(|w| w.ue().set_bit().re().set_bit().te().set_bit());
+
+                    SyncSerial { usart, pins }
+                }
+
+                /// Releases the USART peripheral and associated pins
+                pub fn free(self) -> ($USARTX, (CK, TX, RX)) {
+                    (self.usart, self.pins)
+                }
+            }
+
+            impl<PINS> FullDuplex<u8> for SyncSerial<$USARTX, PINS> {
+                type Error = Error;
+
+                fn read(&mut self) -> nb::Result<u8, Error> {
+                    let isr = self.usart.isr.read();
+
+                    Err(if isr.ore().bit_is_set() {
+                        // clear the flag, otherwise every following read reports the overrun; the
+                        // last received byte is kept in RDR
+                        self.usart.icr.write(|w| w.orecf().set_bit());
+                        nb::Error::Other(Error::Overrun)
+                    } else if isr.rxne().bit_is_set() {
+                        // NOTE(read_volatile) see `write_volatile` below
+                        return Ok(unsafe {
+                            ptr::read_volatile(&self.usart.rdr as *const _ as *const _)
+                        });
+                    } else {
+                        nb::Error::WouldBlock
+                    })
+                }
+
+                fn send(&mut self, byte: u8) -> nb::Result<(), Error> {
+                    if self.usart.isr.read().txe().bit_is_set() {
+                        // NOTE(write_volatile) 8-bit write that's not possible through the svd2rust
+                        // API
+                        // NOTE(addr_of) the pointer is derived from the register block pointer, not
+                        // from a shared reference
+                        unsafe {
+                            let tdr = ptr::addr_of!((*$USARTX::ptr()).tdr) as *mut u8;
+                            ptr::write_volatile(tdr, byte)
+                        }
+                        Ok(())
+                    } else {
+                        Err(nb::Error::WouldBlock)
+                    }
+                }
+            }
+
+            impl<PINS> ::hal::blocking::spi::transfer::Default<u8> for SyncSerial<$USARTX, PINS> {}
+
+            impl<PINS> ::hal::blocking::spi::write::Default<u8> for SyncSerial<$USARTX, PINS> {}
+
+            impl Rx<$USARTX> {
+                /// Returns `true` if the receiver timed out since the flag was last cleared
+                pub fn is_timed_out(&self) -> bool {
+                    // NOTE(unsafe) atomic read with no side effects
+                    unsafe { (*$USARTX::ptr()).isr.read().rtof().bit_is_set() }
+                }
+
+                /// Clears the receiver timeout flag
+                pub fn clear_timeout(&mut self) {
+                    // NOTE(unsafe) atomic write to a stateless register
+                    unsafe { (*$USARTX::ptr()).icr.write(|w| w.rtocf().set_bit()) }
+                }
+
+                /// Returns the status flags (see `Serial::flags`)
+                pub fn flags(&self) -> Flags {
+                    // NOTE(unsafe) atomic read with no side effects
+                    Flags::from_bits_truncate(unsafe { (*$USARTX::ptr()).isr.read().bits() })
+                }
+
+                /// Clears `flags` (see `Serial::clear_flags`)
+                pub fn clear_flags(&mut self, flags: Flags) {
+                    let bits = (flags & Flags::clearable()).bits();
+                    // NOTE(unsafe) atomic write to a stateless register
+                    unsafe { (*$USARTX::ptr()).icr.write(|w| w.bits(bits)) }
+                }
+            }
+
+            impl Tx<$USARTX> {
+                /// Returns the status flags (see `Serial::flags`)
+                pub fn flags(&self) -> Flags {
+                    // NOTE(unsafe) atomic read with no side effects
+                    Flags::from_bits_truncate(unsafe { (*$USARTX::ptr()).isr.read().bits() })
+                }
+
+                /// Clears `flags` (see `Serial::clear_flags`)
+                pub fn clear_flags(&mut self, flags: Flags) {
+                    let bits = (flags & Flags::clearable()).bits();
+                    // NOTE(unsafe) atomic write to a stateless register
+                    unsafe { (*$USARTX::ptr()).icr.write(|w| w.bits(bits)) }
+                }
+            }
+
             impl serial::Read<u8> for Rx<$USARTX> {
                 type Error = Error;
 
@@ -235,12 +690,188 @@ macro_rules! hal {
                     }
                 }
             }
+
+            #[cfg(feature = "panic-serial")]
+            unsafe impl ::panic_serial::PanicTx for Tx<$USARTX> {
+                fn write_byte(byte: u8) {
+                    use hal::serial::Write;
+
+                    let mut tx: Tx<$USARTX> = Tx { _usart: PhantomData };
+                    while tx.write(byte).is_err() {}
+                    while tx.flush().is_err() {}
+                }
+            }
         )+
     }
 }
 
 hal! {
-    USART1: (usart1, APB2, usart1en, usart1rst, pclk2),
-    USART2: (usart2, APB1, usart2en, usart2rst, pclk1),
-    USART3: (usart3, APB1, usart3en, usart3rst, pclk1),
+    USART1: (usart1, usart1_irda, APB2, usart1en, usart1rst, pclk2, USART1_EXTI25),
+    USART2: (usart2, usart2_irda, APB1, usart2en, usart2rst, pclk1, USART2_EXTI26),
+    USART3: (usart3, usart3_irda, APB1, usart3en, usart3rst, pclk1, USART3_EXTI28),
+}
+
+impl<'a, RX> LineReader<'a, RX>
+where
+    RX: serial::Read<u8>,
+{
+    /// Reads from `rx` into `buffer`, which bounds the length of a line (counting a final `\r`)
+    pub fn new(rx: RX, buffer: &'a mut [u8]) -> Self {
+        LineReader {
+            rx,
+            buffer,
+            len: 0,
+            discard: false,
+        }
+    }
+
+    /// Reads all the bytes available and returns the line they complete, without its line ending
+    ///
+    /// Returns `WouldBlock` if no line is complete yet. The line is only valid until the next
+    /// call. After an `Overflow` the bytes up to the next line ending are dropped.
+    pub fn poll(&mut self) -> nb::Result<&str, LineError<RX::Error>> {
+        loop {
+            let byte = match self.rx.read() {
+                Ok(byte) => byte,
+                Err(nb::Error::WouldBlock) => return Err(nb::Error::WouldBlock),
+                Err(nb::Error::Other(e)) => return Err(nb::Error::Other(LineError::Read(e))),
+            };
+
+            if byte == b'\n' {
+                if self.discard {
+                    self.discard = false;
+                    continue;
+                }
+
+                let mut end = self.len;
+                if end > 0 && self.buffer[end - 1] == b'\r' {
+                    end -= 1;
+                }
+                self.len = 0;
+
+                return str::from_utf8(&self.buffer[..end])
+                    .map_err(|_| nb::Error::Other(LineError::Utf8));
+            } else if self.discard {
+                // drop the rest of the line that overflowed
+            } else if self.len == self.buffer.len() {
+                self.len = 0;
+                self.discard = true;
+
+                return Err(nb::Error::Other(LineError::Overflow));
+            } else {
+                self.buffer[self.len] = byte;
+                self.len += 1;
+            }
+        }
+    }
+
+    /// Releases the receiver and the buffer
+    pub fn free(self) -> (RX, &'a mut [u8]) {
+        (self.rx, self.buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hal::serial;
+    use nb;
+    use void::Void;
+
+    use time::Hertz;
+
+    use super::{brr, Flags, LineError, LineReader};
+
+    /// Receiver that goes through `bytes`, with a `WouldBlock` after each `;`
+    struct Rx {
+        bytes: &'static [u8],
+        blocked: bool,
+    }
+
+    impl serial::Read<u8> for Rx {
+        type Error = Void;
+
+        fn read(&mut self) -> nb::Result<u8, Void> {
+            match self.bytes.split_first() {
+                Some((&b';', rest)) => {
+                    self.blocked = !self.blocked;
+                    if self.blocked {
+                        return Err(nb::Error::WouldBlock);
+                    }
+
+                    self.bytes = rest;
+                    self.read()
+                }
+                Some((byte, rest)) => {
+                    self.bytes = rest;
+                    Ok(*byte)
+                }
+                None => Err(nb::Error::WouldBlock),
+            }
+        }
+    }
+
+    fn would_block<T, E>(result: nb::Result<T, E>) -> bool {
+        match result {
+            Err(nb::Error::WouldBlock) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn baud_rate_register() {
+        // oversampling by 16: BRR = USARTDIV
+        assert_eq!(brr(Hertz(72_000_000), 9_600, false), 7_500);
+        assert_eq!(brr(Hertz(8_000_000), 115_200, false), 69);
+
+        // oversampling by 8: BRR[2:0] = USARTDIV[3:0] >> 1 and BRR[3] stays cleared
+        assert_eq!(brr(Hertz(72_000_000), 9_600, true), 0x3a94);
+        assert_eq!(brr(Hertz(443_000), 2_000, true), 0x1b5);
+
+        // fastest rates: USARTDIV = 16
+        assert_eq!(brr(Hertz(16_000_000), 1_000_000, false), 16);
+        assert_eq!(brr(Hertz(8_000_000), 1_000_000, true), 0x10);
+
+        // slowest rates: the divider just fits in 16 bits
+        assert_eq!(brr(Hertz(72_000_000), 1_099, false), 65_514);
+        assert_eq!(brr(Hertz(72_000_000), 2_198, true), 0xffe5);
+    }
+
+    #[test]
+    #[should_panic(expected = "impossible baud rate")]
+    fn baud_rate_too_high() {
+        brr(Hertz(7_000_000), 1_000_000, true);
+    }
+
+    #[test]
+    #[should_panic(expected = "baud rate too low")]
+    fn baud_rate_too_low() {
+        brr(Hertz(72_000_000), 1_098, false);
+    }
+
+    #[test]
+    fn clearable_flags() {
+        // PECF, FECF, NCF, ORECF, IDLECF, TCCF, CTSCF and RTOCF
+        assert_eq!(Flags::clearable().bits(), 0b1010_0101_1111);
+        assert!(!Flags::clearable().contains(Flags::RXNE));
+    }
+
+    #[test]
+    fn lines() {
+        let rx = Rx {
+            bytes: b"he;llo\r\nworld\ntoo long\nok\n",
+            blocked: false,
+        };
+        let mut buffer = [0; 6];
+        let mut reader = LineReader::new(rx, &mut buffer);
+
+        assert!(would_block(reader.poll()));
+        assert_eq!(reader.poll().ok(), Some("hello"));
+        assert_eq!(reader.poll().ok(), Some("world"));
+        match reader.poll() {
+            Err(nb::Error::Other(LineError::Overflow)) => {}
+            _ => panic!(),
+        }
+        assert_eq!(reader.poll().ok(), Some("ok"));
+        assert!(would_block(reader.poll()));
+    }
 }
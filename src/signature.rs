@@ -0,0 +1,66 @@
+//! Device electronic signature
+//!
+//! Factory programmed values stored in the system memory: the 96-bit unique device ID and the size
+//! of the flash memory
+
+use core::ptr;
+
+/// Address of the unique device ID
+const UID: *const u32 = 0x1fff_f7ac as *const u32;
+
+/// Address of the flash size register
+const FLASH_SIZE: *const u16 = 0x1fff_f7cc as *const u16;
+
+/// Returns the 96-bit unique device ID, lowest word first
+pub fn uid() -> [u32; 3] {
+    // NOTE(unsafe) read only memory that's always mapped
+    unsafe {
+        [
+            ptr::read_volatile(UID),
+            ptr::read_volatile(UID.offset(1)),
+            ptr::read_volatile(UID.offset(2)),
+        ]
+    }
+}
+
+/// Returns the unique device ID as 24 uppercase hexadecimal ASCII characters
+///
+/// The bytes are listed in memory order, i.e. the lowest byte of the lowest word comes first
+pub fn uid_hex() -> [u8; 24] {
+    hex(uid())
+}
+
+/// Returns the size of the flash memory in KiB
+pub fn flash_size_kb() -> u16 {
+    // NOTE(unsafe) read only memory that's always mapped
+    unsafe { ptr::read_volatile(FLASH_SIZE) }
+}
+
+fn hex(uid: [u32; 3]) -> [u8; 24] {
+    const DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+    let mut hex = [0; 24];
+    for (i, word) in uid.iter().enumerate() {
+        for j in 0..4 {
+            let byte = (word >> (8 * j)) & 0xff;
+
+            hex[8 * i + 2 * j] = DIGITS[(byte >> 4) as usize];
+            hex[8 * i + 2 * j + 1] = DIGITS[(byte & 0xf) as usize];
+        }
+    }
+
+    hex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::hex;
+
+    #[test]
+    fn uid_hex() {
+        assert_eq!(
+            &hex([0x0403_0201, 0xdead_beef, 0x0000_00a5]),
+            b"01020304EFBEADDEA5000000"
+        );
+    }
+}
@@ -0,0 +1,79 @@
+//! Software PWM on arbitrary output pins
+//!
+//! Useful for pins that aren't connected to a timer channel, e.g. to dim LEDs. The pins are
+//! toggled from an interrupt handler so, unlike hardware PWM, the output frequency is limited by
+//! the interrupt rate and the edges jitter by the interrupt latency (more so if other interrupts
+//! have higher priority).
+//!
+//! Each PWM period is made of 255 ticks, so the timer has to time out at 255 times the desired PWM
+//! frequency; e.g. a 25.5 KHz timer (`Timer::tim2(tim2, 25_500.hz(), ..)`) with its `TimeOut`
+//! event enabled produces a 100 Hz PWM signal.
+
+use hal::digital::OutputPin;
+use hal::timer::CountDown;
+
+/// Software PWM driver for a set of pins
+pub struct SoftPwm<'a, TIM, PIN>
+where
+    PIN: 'a,
+{
+    timer: TIM,
+    pins: &'a mut [PIN],
+    duty: &'a mut [u8],
+    step: u8,
+}
+
+impl<'a, TIM, PIN> SoftPwm<'a, TIM, PIN>
+where
+    TIM: CountDown,
+    PIN: OutputPin,
+{
+    /// Drives `pins` using the time outs of `timer`
+    ///
+    /// `duty` holds the duty cycle of each pin, where 0 is always low and 255 always high; it
+    /// must be as long as `pins`
+    pub fn new(timer: TIM, pins: &'a mut [PIN], duty: &'a mut [u8]) -> Self {
+        assert_eq!(pins.len(), duty.len());
+
+        SoftPwm {
+            timer,
+            pins,
+            duty,
+            step: 0,
+        }
+    }
+
+    /// Sets the duty cycle of the pin at position `pin`
+    pub fn set_duty(&mut self, pin: usize, duty: u8) {
+        self.duty[pin] = duty;
+    }
+
+    /// Returns the duty cycle of the pin at position `pin`
+    pub fn get_duty(&self, pin: usize) -> u8 {
+        self.duty[pin]
+    }
+
+    /// Updates the pins; call this from the timer interrupt handler
+    ///
+    /// Does nothing if the timer hasn't timed out yet, so spurious calls are harmless
+    pub fn tick(&mut self) {
+        if self.timer.wait().is_err() {
+            return;
+        }
+
+        for (pin, duty) in self.pins.iter_mut().zip(self.duty.iter()) {
+            if self.step < *duty {
+                pin.set_high();
+            } else {
+                pin.set_low();
+            }
+        }
+
+        self.step = if self.step == 254 { 0 } else { self.step + 1 };
+    }
+
+    /// Releases the timer
+    pub fn free(self) -> TIM {
+        self.timer
+    }
+}
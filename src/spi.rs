@@ -4,7 +4,7 @@ use core::ptr;
 
 use hal::spi::{FullDuplex, Mode, Phase, Polarity};
 use nb;
-use stm32f30x::{SPI1, SPI2, SPI3};
+use stm32f30x::{Interrupt, SPI1, SPI2, SPI3};
 
 use gpio::gpioa::{PA5, PA6, PA7};
 use gpio::gpiob::{PB3, PB4, PB13, PB14, PB15, PB5};
@@ -26,6 +26,33 @@ pub enum Error {
     _Extensible,
 }
 
+flags! {
+    /// SPI status flags (SR register)
+    pub struct Flags {
+        /// A received byte can be read
+        const RXNE = 0;
+        /// New data can be sent
+        const TXE = 1;
+        /// CRC error
+        const CRCERR = 4;
+        /// Mode fault
+        const MODF = 5;
+        /// Overrun
+        const OVR = 6;
+        /// A transfer is ongoing
+        const BSY = 7;
+    }
+}
+
+/// Order in which the bits of a frame are sent
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BitOrder {
+    /// Most significant bit first; the default
+    MsbFirst,
+    /// Least significant bit first
+    LsbFirst,
+}
+
 // FIXME these should be "closed" traits
 /// SCK pin -- DO NOT IMPLEMENT THIS TRAIT
 pub unsafe trait SckPin<SPI> {}
@@ -99,17 +126,7 @@ macro_rules! hal {
                             w.frxth().set_bit().ds().bits(0b111).ssoe().clear_bit()
                         });
 
-                    let br = match clocks.$pclkX().0 / freq.into().0 {
-                        0 => unreachable!(),
-                        1...2 => 0b000,
-                        3...5 => 0b001,
-                        6...11 => 0b010,
-                        12...23 => 0b011,
-                        24...39 => 0b100,
-                        40...95 => 0b101,
-                        96...191 => 0b110,
-                        _ => 0b111,
-                    };
+                    let br = br(clocks.$pclkX(), freq.into());
 
                     // CPHA: phase
                     // CPOL: polarity
@@ -153,19 +170,196 @@ macro_rules! hal {
                 }
             }
 
+            impl<PINS> Spi<$SPIX, PINS> {
+                /// Changes the SPI mode (clock polarity and phase)
+                ///
+                /// Waits for any ongoing transfer to complete. The data size and the rest of the
+                /// configuration are preserved
+                pub fn set_mode(&mut self, mode: Mode) {
+                    self.disable();
+                    self.spi.cr1.modify(|_, w| {
+                        w.cpha()
+                            .bit(mode.phase == Phase::CaptureOnSecondTransition)
+                            .cpol()
+                            .bit(mode.polarity == Polarity::IdleHigh)
+                    });
+                    self.spi.cr1.modify(|_, w| w.spe().set_bit());
+                }
+
+                /// Changes the SCK frequency
+                ///
+                /// Waits for any ongoing transfer to complete. The data size and the rest of the
+                /// configuration are preserved
+                pub fn set_frequency<F>(&mut self, freq: F, clocks: &Clocks)
+                where
+                    F: Into<Hertz>,
+                {
+                    let br = br(clocks.$pclkX(), freq.into());
+
+                    self.disable();
+                    self.spi.cr1.modify(|_, w| unsafe { w.br().bits(br) });
+                    self.spi.cr1.modify(|_, w| w.spe().set_bit());
+                }
+
+                /// Changes the bit order of the frames
+                ///
+                /// Waits for any ongoing transfer to complete. The data size and the rest of the
+                /// configuration are preserved
+                pub fn set_bit_order(&mut self, order: BitOrder) {
+                    self.disable();
+                    self.spi
+                        .cr1
+                        .modify(|_, w| w.lsbfirst().bit(order == BitOrder::LsbFirst));
+                    self.spi.cr1.modify(|_, w| w.spe().set_bit());
+                }
+
+                /// Enables hardware CRC calculation using the given `polynomial`
+                ///
+                /// This driver uses 8-bit frames so the CRC is 8 bits long as well; the polynomial
+                /// is given without its implicit x^8 term, e.g. `0x07` for CRC-8/SMBUS. Use
+                /// `transfer_crc` to run transfers protected by the CRC.
+                pub fn enable_crc(&mut self, polynomial: u8) {
+                    self.disable();
+                    self.spi
+                        .crcpr
+                        .write(|w| unsafe { w.crcpoly().bits(u16::from(polynomial)) });
+                    // CRCL (named DFF in the SVD): 8-bit CRC
+                    self.spi.cr1.modify(|_, w| w.dff().clear_bit().crcen().set_bit());
+                    self.spi.cr1.modify(|_, w| w.spe().set_bit());
+                }
+
+                /// Disables hardware CRC calculation
+                pub fn disable_crc(&mut self) {
+                    self.disable();
+                    self.spi.cr1.modify(|_, w| w.crcen().clear_bit());
+                    self.spi.cr1.modify(|_, w| w.spe().set_bit());
+                }
+
+                /// Sends `words` followed by their CRC while receiving the same number of bytes
+                /// followed by the CRC computed by the other end
+                ///
+                /// The received bytes replace the contents of `words`. Returns `Error::Crc` if the
+                /// received CRC doesn't match the one computed over the received bytes.
+                /// `enable_crc` must have been called first.
+                pub fn transfer_crc<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Error> {
+                    if words.is_empty() {
+                        return Ok(words);
+                    }
+
+                    // restart the CRC calculation
+                    self.disable();
+                    self.spi.cr1.modify(|_, w| w.crcen().clear_bit());
+                    self.spi.cr1.modify(|_, w| w.crcen().set_bit());
+                    self.spi.cr1.modify(|_, w| w.spe().set_bit());
+
+                    let last = words.len() - 1;
+                    for (i, word) in words.iter_mut().enumerate() {
+                        while self.spi.sr.read().txe().bit_is_clear() {}
+                        // NOTE(write_volatile) see note in `FullDuplex::send`
+                        // NOTE(addr_of) the pointer is derived from the register block pointer, not
+                        // from a shared reference
+                        unsafe {
+                            let dr = ptr::addr_of!((*$SPIX::ptr()).dr) as *mut u8;
+                            ptr::write_volatile(dr, *word)
+                        }
+
+                        if i == last {
+                            // send the CRC right after the last byte
+                            self.spi.cr1.modify(|_, w| w.crcnext().set_bit());
+                        }
+
+                        while self.spi.sr.read().rxne().bit_is_clear() {}
+                        // NOTE(read_volatile) see note in `FullDuplex::read`
+                        *word =
+                            unsafe { ptr::read_volatile(&self.spi.dr as *const _ as *const u8) };
+                    }
+
+                    // drop the received CRC; the hardware already compared it
+                    while self.spi.sr.read().rxne().bit_is_clear() {}
+                    unsafe { ptr::read_volatile(&self.spi.dr as *const _ as *const u8) };
+                    while self.spi.sr.read().bsy().bit_is_set() {}
+
+                    if self.spi.sr.read().crcerr().bit_is_set() {
+                        self.spi.sr.modify(|_, w| w.crcerr().clear_bit());
+                        Err(Error::Crc)
+                    } else {
+                        Ok(words)
+                    }
+                }
+
+                /// Returns the interrupt this SPI peripheral raises
+                pub fn interrupt(&self) -> Interrupt {
+                    Interrupt::$SPIX
+                }
+
+                /// Returns the status flags
+                pub fn flags(&self) -> Flags {
+                    Flags::from_bits_truncate(self.spi.sr.read().bits())
+                }
+
+                /// Clears the `CRCERR`, `MODF` and / or `OVR` error flags
+                ///
+                /// Clearing `OVR` drops the oldest unread byte. A mode fault disables the
+                /// peripheral and drops it out of master mode; clearing `MODF` restores both. The
+                /// other flags follow the data register and the bus, and are ignored.
+                pub fn clear_flags(&mut self, flags: Flags) {
+                    if flags.contains(Flags::OVR) {
+                        // OVR is cleared by reading DR and then SR
+                        // NOTE(read_volatile) see note in `FullDuplex::read`
+                        unsafe { ptr::read_volatile(&self.spi.dr as *const _ as *const u8) };
+                        self.spi.sr.read();
+                    }
+
+                    if flags.contains(Flags::MODF) {
+                        // MODF is cleared by reading SR and then writing CR1
+                        self.spi.sr.read();
+                        self.spi.cr1.modify(|_, w| w.mstr().set_bit());
+                        self.spi.cr1.modify(|_, w| w.spe().set_bit());
+                    }
+
+                    if flags.contains(Flags::CRCERR) {
+                        // CRCERR is cleared by writing 0 to it
+                        self.spi.sr.modify(|_, w| w.crcerr().clear_bit());
+                    }
+                }
+
+                /// Clears the error flag that's set, if any, and returns the matching error
+                fn take_error(&mut self) -> Option<Error> {
+                    let flags = self.flags();
+
+                    let (flag, error) = if flags.contains(Flags::OVR) {
+                        (Flags::OVR, Error::Overrun)
+                    } else if flags.contains(Flags::MODF) {
+                        (Flags::MODF, Error::ModeFault)
+                    } else if flags.contains(Flags::CRCERR) {
+                        (Flags::CRCERR, Error::Crc)
+                    } else {
+                        return None;
+                    };
+
+                    self.clear_flags(flag);
+
+                    Some(error)
+                }
+
+                fn disable(&mut self) {
+                    // CR1 can only be changed while the peripheral is idle
+                    while self.spi.sr.read().bsy().bit_is_set() {}
+                    self.spi.cr1.modify(|_, w| w.spe().clear_bit());
+                }
+            }
+
             impl<PINS> FullDuplex<u8> for Spi<$SPIX, PINS> {
                 type Error = Error;
 
                 fn read(&mut self) -> nb::Result<u8, Error> {
+                    if let Some(error) = self.take_error() {
+                        return Err(nb::Error::Other(error));
+                    }
+
                     let sr = self.spi.sr.read();
 
-                    Err(if sr.ovr().bit_is_set() {
-                        nb::Error::Other(Error::Overrun)
-                    } else if sr.modf().bit_is_set() {
-                        nb::Error::Other(Error::ModeFault)
-                    } else if sr.crcerr().bit_is_set() {
-                        nb::Error::Other(Error::Crc)
-                    } else if sr.rxne().bit_is_set() {
+                    Err(if sr.rxne().bit_is_set() {
                         // NOTE(read_volatile) read only 1 byte (the svd2rust API only allows
                         // reading a half-word)
                         return Ok(unsafe {
@@ -177,15 +371,13 @@ macro_rules! hal {
                 }
 
                 fn send(&mut self, byte: u8) -> nb::Result<(), Error> {
+                    if let Some(error) = self.take_error() {
+                        return Err(nb::Error::Other(error));
+                    }
+
                     let sr = self.spi.sr.read();
 
-                    Err(if sr.ovr().bit_is_set() {
-                        nb::Error::Other(Error::Overrun)
-                    } else if sr.modf().bit_is_set() {
-                        nb::Error::Other(Error::ModeFault)
-                    } else if sr.crcerr().bit_is_set() {
-                        nb::Error::Other(Error::Crc)
-                    } else if sr.txe().bit_is_set() {
+                    Err(if sr.txe().bit_is_set() {
                         // NOTE(write_volatile) see note above
                         unsafe { ptr::write_volatile(&self.spi.dr as *const _ as *mut u8, byte) }
                         return Ok(());
@@ -202,6 +394,20 @@ macro_rules! hal {
     }
 }
 
+fn br(pclk: Hertz, freq: Hertz) -> u8 {
+    match pclk.0 / freq.0 {
+        0 => unreachable!(),
+        1...2 => 0b000,
+        3...5 => 0b001,
+        6...11 => 0b010,
+        12...23 => 0b011,
+        24...39 => 0b100,
+        40...95 => 0b101,
+        96...191 => 0b110,
+        _ => 0b111,
+    }
+}
+
 hal! {
     SPI1: (spi1, APB2, spi1en, spi1rst, pclk2),
     SPI2: (spi2, APB1, spi2en, spi2rst, pclk1),
@@ -248,3 +454,17 @@ hal! {
 //         Ok(())
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::Flags;
+
+    #[test]
+    fn flags() {
+        // RXNE, TXE, CRCERR, MODF, OVR and BSY, as in SPIx_SR
+        assert_eq!(Flags::all().bits(), 0b1111_0011);
+        assert_eq!(Flags::TXE.bits(), 1 << 1);
+        assert_eq!(Flags::CRCERR.bits(), 1 << 4);
+        assert_eq!(Flags::BSY.bits(), 1 << 7);
+    }
+}
@@ -0,0 +1,119 @@
+//! System configuration controller (SYSCFG)
+
+use stm32f30x::{syscfg, SYSCFG};
+
+use rcc::APB2;
+
+/// Extension trait to constrain the SYSCFG peripheral
+pub trait SyscfgExt {
+    /// Constrains the SYSCFG peripheral to play nicely with the other abstractions
+    ///
+    /// This enables the SYSCFG clock. The peripheral is not reset, as that would undo the memory
+    /// mapping selected by the BOOT pins.
+    fn constrain(self, apb2: &mut APB2) -> Parts;
+}
+
+impl SyscfgExt for SYSCFG {
+    fn constrain(self, apb2: &mut APB2) -> Parts {
+        apb2.enr().modify(|_, w| w.syscfgen().enabled());
+
+        Parts {
+            cfgr1: CFGR1 { _0: () },
+        }
+    }
+}
+
+/// Constrained SYSCFG peripheral
+pub struct Parts {
+    /// Opaque CFGR1 register
+    pub cfgr1: CFGR1,
+}
+
+/// Memory mapped at address 0
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemoryMap {
+    /// Main flash memory
+    MainFlash,
+    /// System memory, i.e. the ST bootloader
+    SystemMemory,
+    /// Embedded SRAM
+    Sram,
+}
+
+/// I2C pads, or I2C peripherals, whose Fast-mode Plus drive can be enabled
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FastModePlus {
+    /// PB6 pad
+    PB6,
+    /// PB7 pad
+    PB7,
+    /// PB8 pad
+    PB8,
+    /// PB9 pad
+    PB9,
+    /// All the pads used by I2C1
+    I2C1,
+    /// All the pads used by I2C2
+    I2C2,
+}
+
+impl FastModePlus {
+    fn mask(&self) -> u32 {
+        1 << match *self {
+            FastModePlus::PB6 => 16,
+            FastModePlus::PB7 => 17,
+            FastModePlus::PB8 => 18,
+            FastModePlus::PB9 => 19,
+            FastModePlus::I2C1 => 20,
+            FastModePlus::I2C2 => 21,
+        }
+    }
+}
+
+/// Opaque CFGR1 register
+pub struct CFGR1 {
+    _0: (),
+}
+
+impl CFGR1 {
+    pub(crate) fn cfgr1(&mut self) -> &syscfg::CFGR1 {
+        // NOTE(unsafe) this proxy grants exclusive access to this register
+        unsafe { &(*SYSCFG::ptr()).cfgr1 }
+    }
+
+    /// Returns the memory currently mapped at address 0
+    pub fn memory_map(&mut self) -> MemoryMap {
+        match self.cfgr1().read().mem_mode().bits() {
+            0b00 | 0b10 => MemoryMap::MainFlash,
+            0b01 => MemoryMap::SystemMemory,
+            _ => MemoryMap::Sram,
+        }
+    }
+
+    /// Maps `memory` at address 0
+    ///
+    /// NOTE the vector table is fetched from address 0 unless VTOR says otherwise, so the
+    /// remapped memory must hold a valid vector table before an interrupt fires
+    pub fn memory_remap(&mut self, memory: MemoryMap) {
+        let bits = match memory {
+            MemoryMap::MainFlash => 0b00,
+            MemoryMap::SystemMemory => 0b01,
+            MemoryMap::Sram => 0b11,
+        };
+
+        self.cfgr1().modify(|_, w| unsafe { w.mem_mode().bits(bits) });
+    }
+
+    /// Enables or disables the Fast-mode Plus (20 mA) drive of `pads`
+    pub fn set_fast_mode_plus(&mut self, pads: FastModePlus, enable: bool) {
+        let mask = pads.mask();
+
+        self.cfgr1().modify(|r, w| unsafe {
+            w.bits(if enable {
+                r.bits() | mask
+            } else {
+                r.bits() & !mask
+            })
+        });
+    }
+}
@@ -1,11 +1,18 @@
 //! Timers
 
-use cast::{u16, u32};
+use cast::{u16, u32, u64};
+use hal::blocking::delay::DelayMs;
 use hal::timer::{CountDown, Periodic};
 use nb;
-use stm32f30x::{TIM2, TIM3, TIM4, TIM6, TIM7};
+use stm32f30x::{Interrupt, TIM2, TIM3, TIM4, TIM6, TIM7};
 use void::Void;
 
+use gpio::gpioa::{PA0, PA11, PA15, PA5, PA6};
+use gpio::gpiob::{PB4, PB6};
+use gpio::gpioc::PC6;
+use gpio::gpiod::{PD12, PD3};
+use gpio::gpioe::PE2;
+use gpio::{AF1, AF10, AF2};
 use rcc::{APB1, Clocks};
 use time::Hertz;
 
@@ -16,14 +23,271 @@ pub struct Timer<TIM> {
     timeout: Hertz,
 }
 
+// FIXME these should be "closed" traits
+/// Channel 1 pin -- DO NOT IMPLEMENT THIS TRAIT
+pub unsafe trait Ch1Pin<TIM> {}
+
+unsafe impl Ch1Pin<TIM2> for PA0<AF1> {}
+unsafe impl Ch1Pin<TIM2> for PA5<AF1> {}
+unsafe impl Ch1Pin<TIM2> for PA15<AF1> {}
+unsafe impl Ch1Pin<TIM2> for PD3<AF2> {}
+
+unsafe impl Ch1Pin<TIM3> for PA6<AF2> {}
+unsafe impl Ch1Pin<TIM3> for PB4<AF2> {}
+unsafe impl Ch1Pin<TIM3> for PC6<AF2> {}
+unsafe impl Ch1Pin<TIM3> for PE2<AF2> {}
+
+unsafe impl Ch1Pin<TIM4> for PA11<AF10> {}
+unsafe impl Ch1Pin<TIM4> for PB6<AF2> {}
+unsafe impl Ch1Pin<TIM4> for PD12<AF2> {}
+
+/// Timer operating in PWM input mode
+///
+/// Measures both the period and the pulse width of a PWM signal applied to the channel 1 pin:
+/// every rising edge resets the counter and latches the period into CCR1 while the following
+/// falling edge latches the pulse width into CCR2.
+pub struct PwmInput<TIM, PIN> {
+    clocks: Clocks,
+    tim: TIM,
+    pin: PIN,
+}
+
+/// Timer operating in external clock mode, counting the rising edges applied to the channel 1
+/// pin
+///
+/// The edges are resynchronized to the timer clock so the input frequency must stay below a
+/// third of it (see `timer_clock`). Counts up to 65535 edges per gate period.
+pub struct FrequencyCounter<TIM, PIN> {
+    clocks: Clocks,
+    tim: TIM,
+    pin: PIN,
+}
+
+/// Two 16-bit timers chained into a free running 32-bit counter
+///
+/// The `LO` timer counts the ticks and, on every overflow, clocks the `HI` timer through its
+/// trigger output, so the pair wraps around after 2^32 ticks instead of 2^16. TIM2 is a 32-bit
+/// timer on its own; this is for TIM3 and TIM4.
+pub struct ChainedTimer<LO, HI> {
+    lo: LO,
+    hi: HI,
+    tick: Hertz,
+}
+
+flags! {
+    /// Timer status flags (SR register)
+    ///
+    /// The basic timers (TIM6, TIM7) only have `UIF`
+    pub struct Flags {
+        /// Update event, e.g. the counter overflowed
+        const UIF = 0;
+        /// Channel 1 capture / compare
+        const CC1IF = 1;
+        /// Channel 2 capture / compare
+        const CC2IF = 2;
+        /// Channel 3 capture / compare
+        const CC3IF = 3;
+        /// Channel 4 capture / compare
+        const CC4IF = 4;
+        /// Trigger input event
+        const TIF = 6;
+        /// Channel 1 overcapture
+        const CC1OF = 9;
+        /// Channel 2 overcapture
+        const CC2OF = 10;
+        /// Channel 3 overcapture
+        const CC3OF = 11;
+        /// Channel 4 overcapture
+        const CC4OF = 12;
+    }
+}
+
+/// Peripheral bus a timer hangs from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Bus {
+    /// APB1: TIM2, TIM3, TIM4, TIM6 and TIM7
+    Apb1,
+    /// APB2: TIM1, TIM8, TIM15, TIM16 and TIM17
+    Apb2,
+}
+
+/// Returns the frequency of the clock that drives the timers of `bus`
+///
+/// That's the bus clock if the bus isn't divided from HCLK and twice the bus clock otherwise
+pub fn timer_clock(clocks: &Clocks, bus: Bus) -> Hertz {
+    match bus {
+        Bus::Apb1 => timclk(clocks.pclk1(), clocks.ppre1()),
+        Bus::Apb2 => timclk(clocks.pclk2(), clocks.ppre2()),
+    }
+}
+
+fn timclk(pclk: Hertz, ppre: u8) -> Hertz {
+    Hertz(pclk.0 * if ppre == 1 { 1 } else { 2 })
+}
+
+/// Returns the number of duty cycle steps (ARR + 1) a timer clocked at `timclk` offers at a PWM
+/// frequency of `freq`
+///
+/// The prescaler is picked as in `CountDown::start`: as small as possible, to keep most of the
+/// resolution. Returns 0 if `freq` is higher than `timclk`.
+pub fn pwm_resolution(timclk: Hertz, freq: Hertz) -> u16 {
+    let ticks = timclk.0 / freq.0;
+    if ticks == 0 {
+        return 0;
+    }
+
+    let psc = (ticks - 1) / (1 << 16);
+    u16(ticks / (psc + 1)).unwrap_or(0xffff)
+}
+
 /// Interrupt events
 pub enum Event {
     /// Timer timed out / count down ended
     TimeOut,
 }
 
+/// Counting mode of a general purpose timer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Alignment {
+    /// The counter counts up from 0 to the auto-reload value
+    Edge,
+    /// The counter counts up and down; compare flags are set while counting up
+    CenterUp,
+    /// The counter counts up and down; compare flags are set while counting down
+    CenterDown,
+    /// The counter counts up and down; compare flags are set in both directions
+    CenterBoth,
+}
+
+impl Alignment {
+    fn bits(&self) -> u8 {
+        match *self {
+            Alignment::Edge => 0b00,
+            Alignment::CenterDown => 0b01,
+            Alignment::CenterUp => 0b10,
+            Alignment::CenterBoth => 0b11,
+        }
+    }
+}
+
+/// Signal a master timer sends on its trigger output (TRGO)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MasterMode {
+    /// The counter is reset (UG bit or the slave mode reset)
+    Reset,
+    /// The counter is enabled (CEN bit)
+    Enable,
+    /// Update event, i.e. every time the counter overflows
+    Update,
+    /// Pulse on every capture / compare match of channel 1
+    ComparePulse,
+    /// Output compare reference signal of channel 1
+    Compare1,
+    /// Output compare reference signal of channel 2
+    Compare2,
+    /// Output compare reference signal of channel 3
+    Compare3,
+    /// Output compare reference signal of channel 4
+    Compare4,
+}
+
+impl MasterMode {
+    fn bits(&self) -> u8 {
+        match *self {
+            MasterMode::Reset => 0b000,
+            MasterMode::Enable => 0b001,
+            MasterMode::Update => 0b010,
+            MasterMode::ComparePulse => 0b011,
+            MasterMode::Compare1 => 0b100,
+            MasterMode::Compare2 => 0b101,
+            MasterMode::Compare3 => 0b110,
+            MasterMode::Compare4 => 0b111,
+        }
+    }
+}
+
+/// Signal a basic timer (TIM6, TIM7) sends on its trigger output (TRGO)
+///
+/// The basic timers have no capture / compare channels, so only these three are available
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BasicMasterMode {
+    /// The counter is reset (UG bit)
+    Reset,
+    /// The counter is enabled (CEN bit)
+    Enable,
+    /// Update event, i.e. every time the counter overflows
+    Update,
+}
+
+impl BasicMasterMode {
+    fn bits(&self) -> u8 {
+        match *self {
+            BasicMasterMode::Reset => 0b000,
+            BasicMasterMode::Enable => 0b001,
+            BasicMasterMode::Update => 0b010,
+        }
+    }
+}
+
+/// How a slave timer reacts to its trigger input
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SlaveMode {
+    /// The trigger input is ignored
+    Disabled,
+    /// A rising edge of the trigger restarts the counter
+    Reset,
+    /// The counter only runs while the trigger is high
+    Gated,
+    /// A rising edge of the trigger starts the counter
+    Trigger,
+    /// The counter is clocked by the rising edges of the trigger
+    ExternalClock,
+}
+
+impl SlaveMode {
+    fn bits(&self) -> u8 {
+        match *self {
+            SlaveMode::Disabled => 0b000,
+            SlaveMode::Reset => 0b100,
+            SlaveMode::Gated => 0b101,
+            SlaveMode::Trigger => 0b110,
+            SlaveMode::ExternalClock => 0b111,
+        }
+    }
+}
+
+/// Internal trigger input, i.e. the TRGO of another timer
+///
+/// | Slave | ITR0 | ITR1 | ITR2  | ITR3 |
+/// |-------|------|------|-------|------|
+/// | TIM2  | TIM1 | TIM8 | TIM3  | TIM4 |
+/// | TIM3  | TIM1 | TIM2 | TIM15 | TIM4 |
+/// | TIM4  | TIM1 | TIM2 | TIM3  | TIM8 |
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InternalTrigger {
+    /// ITR0
+    Itr0,
+    /// ITR1
+    Itr1,
+    /// ITR2
+    Itr2,
+    /// ITR3
+    Itr3,
+}
+
+impl InternalTrigger {
+    fn bits(&self) -> u8 {
+        match *self {
+            InternalTrigger::Itr0 => 0b000,
+            InternalTrigger::Itr1 => 0b001,
+            InternalTrigger::Itr2 => 0b010,
+            InternalTrigger::Itr3 => 0b011,
+        }
+    }
+}
+
 macro_rules! hal {
-    ($($TIM:ident: ($tim:ident, $timXen:ident, $timXrst:ident),)+) => {
+    ($($TIM:ident: ($tim:ident, $timXen:ident, $timXrst:ident, $INTERRUPT:ident),)+) => {
         $(
             impl Periodic for Timer<$TIM> {}
 
@@ -45,8 +309,7 @@ macro_rules! hal {
                     self.timeout = timeout.into();
 
                     let frequency = self.timeout.0;
-                    let ticks = self.clocks.pclk1().0 * if self.clocks.ppre1() == 1 { 1 } else { 2 }
-                        / frequency;
+                    let ticks = timer_clock(&self.clocks, Bus::Apb1).0 / frequency;
 
                     let psc = u16((ticks - 1) / (1 << 16)).unwrap();
                     self.tim.psc.write(|w| unsafe { w.psc().bits(psc) });
@@ -54,6 +317,13 @@ macro_rules! hal {
                     let arr = u16(ticks / u32(psc + 1)).unwrap();
                     self.tim.arr.write(|w| unsafe { w.bits(u32(arr)) });
 
+                    if self.tim.cr1.read().arpe().bit_is_set() {
+                        // the new ARR sits in the preload register until the next update event;
+                        // force one so it applies to this period, and drop the UIF it raises
+                        self.tim.egr.write(|w| w.ug().set_bit());
+                        self.tim.sr.modify(|_, w| w.uif().clear_bit());
+                    }
+
                     // start counter
                     self.tim.cr1.modify(|_, w| w.cen().set_bit());
                 }
@@ -112,6 +382,53 @@ macro_rules! hal {
                     }
                 }
 
+                /// Returns the current value of the counter
+                ///
+                /// The counter goes from 0 up to the auto-reload value once per timeout period
+                pub fn counter(&self) -> u16 {
+                    u16(self.tim.cnt.read().bits()).unwrap()
+                }
+
+                /// Returns the time left, in microseconds, until the current period expires
+                pub fn remaining(&self) -> u32 {
+                    let arr = self.tim.arr.read().bits();
+                    let cnt = self.tim.cnt.read().bits();
+                    let psc = u32(self.tim.psc.read().psc().bits());
+
+                    let timclk = timer_clock(&self.clocks, Bus::Apb1).0;
+                    let ticks = u64(arr.saturating_sub(cnt)) * u64(psc + 1);
+
+                    u32(ticks * 1_000_000 / u64(timclk)).unwrap()
+                }
+
+                /// Returns the interrupt this timer raises
+                pub fn interrupt(&self) -> Interrupt {
+                    Interrupt::$INTERRUPT
+                }
+
+                /// Returns the status flags
+                pub fn flags(&self) -> Flags {
+                    Flags::from_bits_truncate(self.tim.sr.read().bits())
+                }
+
+                /// Clears `flags`
+                ///
+                /// NOTE clearing `UIF` makes the next `wait` block until the following time out
+                pub fn clear_flags(&mut self, flags: Flags) {
+                    // the flags are cleared by writing 0 to them; writing 1 has no effect
+                    self.tim.sr.write(|w| unsafe { w.bits(!flags.bits()) });
+                }
+
+                /// Enables or disables the auto-reload preload (ARPE)
+                ///
+                /// When enabled the auto-reload value is buffered and only loaded into the counter
+                /// logic at update events. `start` always pauses the timer and restarts the
+                /// counter from 0, cutting the current period short, and forces an update event
+                /// in this mode so the new period applies right away, as it does without preload.
+                pub fn set_auto_reload_preload(&mut self, enable: bool) {
+                    self.tim.cr1.modify(|_, w| w.arpe().bit(enable));
+                }
+
                 /// Releases the TIM peripheral
                 pub fn free(self) -> $TIM {
                     // pause counter
@@ -124,9 +441,439 @@ macro_rules! hal {
 }
 
 hal! {
+    TIM2: (tim2, tim2en, tim2rst, TIM2),
+    TIM3: (tim3, tim3en, tim3rst, TIM3),
+    TIM4: (tim4, tim4en, tim4rst, TIM4),
+    TIM6: (tim6, tim6en, tim6rst, TIM6_DACUNDER),
+    TIM7: (tim7, tim7en, tim7rst, TIM7),
+}
+
+macro_rules! pwm_input {
+    ($($TIM:ident: ($tim:ident, $timXen:ident, $timXrst:ident),)+) => {
+        $(
+            impl<PIN> PwmInput<$TIM, PIN> {
+                /// Configures a TIM peripheral to measure a PWM signal applied to `pin`
+                ///
+                /// `min_frequency` is the lowest input frequency that needs to be measured; it's
+                /// used to pick the prescaler so a full period fits in 16 bits of the counter,
+                /// trading off resolution
+                ///
+                /// # Panics
+                ///
+                /// If `min_frequency` is zero
+                pub fn $tim<F>(
+                    tim: $TIM,
+                    pin: PIN,
+                    min_frequency: F,
+                    clocks: Clocks,
+                    apb1: &mut APB1,
+                ) -> Self
+                where
+                    F: Into<Hertz>,
+                    PIN: Ch1Pin<$TIM>,
+                {
+                    let min_frequency = min_frequency.into().0;
+                    assert!(min_frequency != 0, "`min_frequency` must be at least 1 Hz");
+
+                    // enable and reset peripheral to a clean slate state
+                    apb1.enr().modify(|_, w| w.$timXen().set_bit());
+                    apb1.rstr().modify(|_, w| w.$timXrst().set_bit());
+                    apb1.rstr().modify(|_, w| w.$timXrst().clear_bit());
+
+                    let pwm = PwmInput { clocks, tim, pin };
+
+                    // NOTE the timer clock is below 2^32 Hz so even at 1 Hz a 16-bit prescaler
+                    // is enough
+                    let ticks = pwm.timclk() / min_frequency;
+                    let psc = u16(ticks / (1 << 16)).unwrap();
+                    pwm.tim.psc.write(|w| unsafe { w.psc().bits(psc) });
+                    pwm.tim.arr.write(|w| unsafe { w.bits(0xffff) });
+
+                    // CC1S = 0b01: IC1 mapped on TI1
+                    // CC2S = 0b10: IC2 mapped on TI1
+                    // NOTE(unsafe) the svd2rust API only exposes this register through its output
+                    // compare layout
+                    pwm.tim.ccmr1_output.write(|w| unsafe { w.bits(0b10 << 8 | 0b01) });
+
+                    // IC1 captures on the rising edge, IC2 on the falling edge
+                    pwm.tim.ccer.write(|w| {
+                        w.cc1p()
+                            .clear_bit()
+                            .cc1np()
+                            .clear_bit()
+                            .cc1e()
+                            .set_bit()
+                            .cc2p()
+                            .set_bit()
+                            .cc2np()
+                            .clear_bit()
+                            .cc2e()
+                            .set_bit()
+                    });
+
+                    // TS = 0b101: trigger on TI1FP1
+                    // SMS = 0b100: reset mode, i.e. restart the counter on every rising edge
+                    pwm.tim.smcr.write(|w| unsafe { w.ts().bits(0b101).sms().bits(0b100) });
+
+                    // load the prescaler and start counting
+                    pwm.tim.egr.write(|w| w.ug().set_bit());
+                    pwm.tim.cr1.modify(|_, w| w.cen().set_bit());
+
+                    pwm
+                }
+
+                /// Returns the frequency of the input signal
+                ///
+                /// Returns `None` until a full period has been captured
+                pub fn read_frequency(&self) -> Option<Hertz> {
+                    let period = self.tim.ccr1.read().bits();
+
+                    if period == 0 {
+                        None
+                    } else {
+                        let psc = u32(self.tim.psc.read().psc().bits());
+
+                        Some(Hertz(self.timclk() / (psc + 1) / period))
+                    }
+                }
+
+                /// Returns the pulse width and the period of the input signal, in timer ticks
+                ///
+                /// The duty cycle is the ratio between the two. Returns `None` until a full period
+                /// has been captured
+                pub fn read_duty(&self) -> Option<(u32, u32)> {
+                    let period = self.tim.ccr1.read().bits();
+
+                    if period == 0 {
+                        None
+                    } else {
+                        Some((self.tim.ccr2.read().bits(), period))
+                    }
+                }
+
+                /// Releases the TIM peripheral and the input pin
+                pub fn free(self) -> ($TIM, PIN) {
+                    // pause counter
+                    self.tim.cr1.modify(|_, w| w.cen().clear_bit());
+                    (self.tim, self.pin)
+                }
+
+                fn timclk(&self) -> u32 {
+                    timer_clock(&self.clocks, Bus::Apb1).0
+                }
+            }
+        )+
+    }
+}
+
+macro_rules! frequency_counter {
+    ($($TIM:ident: ($tim:ident, $timXen:ident, $timXrst:ident),)+) => {
+        $(
+            impl<PIN> FrequencyCounter<$TIM, PIN> {
+                /// Configures a TIM peripheral to count the rising edges applied to `pin`
+                pub fn $tim(tim: $TIM, pin: PIN, clocks: Clocks, apb1: &mut APB1) -> Self
+                where
+                    PIN: Ch1Pin<$TIM>,
+                {
+                    // enable and reset peripheral to a clean slate state
+                    apb1.enr().modify(|_, w| w.$timXen().set_bit());
+                    apb1.rstr().modify(|_, w| w.$timXrst().set_bit());
+                    apb1.rstr().modify(|_, w| w.$timXrst().clear_bit());
+
+                    tim.arr.write(|w| unsafe { w.bits(0xffff) });
+
+                    // CC1S = 0b01: IC1 mapped on TI1
+                    // NOTE(unsafe) the svd2rust API only exposes this register through its output
+                    // compare layout
+                    tim.ccmr1_output.write(|w| unsafe { w.bits(0b01) });
+
+                    // count the rising edges
+                    tim.ccer.write(|w| w.cc1p().clear_bit().cc1np().clear_bit());
+
+                    // TS = 0b101: trigger on TI1FP1
+                    // SMS = 0b111: external clock mode 1, i.e. the trigger clocks the counter
+                    tim.smcr.write(|w| unsafe { w.ts().bits(0b101).sms().bits(0b111) });
+
+                    tim.cr1.modify(|_, w| w.cen().set_bit());
+
+                    FrequencyCounter { clocks, tim, pin }
+                }
+
+                /// Returns the number of edges counted since the last `reset`, modulo 2^16
+                pub fn count(&self) -> u16 {
+                    u16(self.tim.cnt.read().bits() & 0xffff).unwrap()
+                }
+
+                /// Restarts counting from zero
+                pub fn reset(&mut self) {
+                    self.tim.cnt.reset();
+                    self.tim.sr.modify(|_, w| w.uif().clear_bit());
+                }
+
+                /// Counts the edges for `gate_ms` milliseconds, timed by `delay`, and returns the
+                /// input frequency
+                ///
+                /// Returns `None` if more than 65535 edges came in; use a shorter gate period.
+                /// Longer gate periods give a finer resolution: 1 / `gate_ms` KHz.
+                pub fn measure<D>(&mut self, delay: &mut D, gate_ms: u16) -> Option<Hertz>
+                where
+                    D: DelayMs<u16>,
+                {
+                    assert!(gate_ms != 0);
+
+                    self.reset();
+                    delay.delay_ms(gate_ms);
+                    let count = u32(self.count());
+
+                    if self.tim.sr.read().uif().bit_is_set() {
+                        None
+                    } else {
+                        Some(Hertz(count * 1_000 / u32(gate_ms)))
+                    }
+                }
+
+                /// Returns the highest input frequency that can be counted reliably
+                pub fn max_frequency(&self) -> Hertz {
+                    Hertz(timer_clock(&self.clocks, Bus::Apb1).0 / 3)
+                }
+
+                /// Releases the TIM peripheral and the input pin
+                pub fn free(self) -> ($TIM, PIN) {
+                    // pause counter
+                    self.tim.cr1.modify(|_, w| w.cen().clear_bit());
+                    (self.tim, self.pin)
+                }
+            }
+        )+
+    }
+}
+
+frequency_counter! {
     TIM2: (tim2, tim2en, tim2rst),
     TIM3: (tim3, tim3en, tim3rst),
     TIM4: (tim4, tim4en, tim4rst),
-    TIM6: (tim6, tim6en, tim6rst),
-    TIM7: (tim7, tim7en, tim7rst),
+}
+
+pwm_input! {
+    TIM2: (tim2, tim2en, tim2rst),
+    TIM3: (tim3, tim3en, tim3rst),
+    TIM4: (tim4, tim4en, tim4rst),
+}
+
+macro_rules! master_slave {
+    ($($TIM:ident,)+) => {
+        $(
+            impl Timer<$TIM> {
+                /// Selects what this timer sends to other timers through its TRGO output
+                pub fn set_master_mode(&mut self, mode: MasterMode) {
+                    self.tim.cr2.modify(|_, w| unsafe { w.mms().bits(mode.bits()) });
+                }
+
+                /// Makes this timer a slave of the timer connected to `trigger`
+                pub fn set_slave_mode(&mut self, mode: SlaveMode, trigger: InternalTrigger) {
+                    // the trigger has to be selected while the slave mode is disabled
+                    self.tim.smcr.modify(|_, w| unsafe { w.sms().bits(0b000) });
+                    self.tim.smcr.modify(|_, w| unsafe { w.ts().bits(trigger.bits()) });
+                    self.tim.smcr.modify(|_, w| unsafe { w.sms().bits(mode.bits()) });
+                }
+            }
+        )+
+    }
+}
+
+master_slave! {
+    TIM2,
+    TIM3,
+    TIM4,
+}
+
+macro_rules! chained {
+    ($($LO:ident, $HI:ident: (
+        $lo_hi:ident,
+        $loXen:ident,
+        $loXrst:ident,
+        $hiXen:ident,
+        $hiXrst:ident,
+        $ITR:ident
+    ),)+) => {
+        $(
+            impl ChainedTimer<$LO, $HI> {
+                /// Chains two timers into a 32-bit counter that increases at `tick` frequency
+                ///
+                /// The timer clock is divided by an integer so the actual rate, returned by
+                /// `tick`, may be slightly higher than requested
+                ///
+                /// # Panics
+                ///
+                /// If `tick` is higher than the timer clock or lower than 1 / 65536 of it
+                pub fn $lo_hi<T>(lo: $LO, hi: $HI, tick: T, clocks: Clocks, apb1: &mut APB1) -> Self
+                where
+                    T: Into<Hertz>,
+                {
+                    // enable and reset peripherals to a clean slate state
+                    apb1.enr().modify(|_, w| w.$loXen().set_bit().$hiXen().set_bit());
+                    apb1.rstr().modify(|_, w| w.$loXrst().set_bit().$hiXrst().set_bit());
+                    apb1.rstr().modify(|_, w| w.$loXrst().clear_bit().$hiXrst().clear_bit());
+
+                    let timclk = timer_clock(&clocks, Bus::Apb1).0;
+                    let psc = timclk / tick.into().0;
+                    assert!(psc != 0);
+                    // the prescaler only divides by integers; report the rate actually programmed
+                    let tick = Hertz(timclk / psc);
+                    let psc = u16(psc - 1).unwrap();
+
+                    lo.psc.write(|w| unsafe { w.psc().bits(psc) });
+                    lo.arr.write(|w| unsafe { w.bits(0xffff) });
+                    // load the prescaler now rather than at the first overflow; this is done
+                    // before selecting the master mode so the slave doesn't count this update
+                    lo.egr.write(|w| w.ug().set_bit());
+
+                    // the slave counts the update events of the master
+                    hi.arr.write(|w| unsafe { w.bits(0xffff) });
+                    hi.smcr.write(|w| unsafe {
+                        w.ts()
+                            .bits(InternalTrigger::$ITR.bits())
+                            .sms()
+                            .bits(SlaveMode::ExternalClock.bits())
+                    });
+                    hi.cr1.modify(|_, w| w.cen().set_bit());
+
+                    lo.cr2.write(|w| unsafe { w.mms().bits(MasterMode::Update.bits()) });
+                    lo.cr1.modify(|_, w| w.cen().set_bit());
+
+                    ChainedTimer { lo, hi, tick }
+                }
+
+                /// Returns the number of ticks since the pair was started or `reset`, modulo 2^32
+                pub fn count(&self) -> u32 {
+                    // the low half may overflow between the reads of the two halves; read the
+                    // high half again to detect that and retry
+                    loop {
+                        let hi = self.hi.cnt.read().bits() & 0xffff;
+                        let lo = self.lo.cnt.read().bits() & 0xffff;
+
+                        if self.hi.cnt.read().bits() & 0xffff == hi {
+                            return (hi << 16) | lo;
+                        }
+                    }
+                }
+
+                /// Restarts counting from zero
+                pub fn reset(&mut self) {
+                    self.lo.cr1.modify(|_, w| w.cen().clear_bit());
+                    self.lo.cnt.reset();
+                    self.hi.cnt.reset();
+                    self.lo.cr1.modify(|_, w| w.cen().set_bit());
+                }
+
+                /// Returns the frequency at which the counter increases
+                pub fn tick(&self) -> Hertz {
+                    self.tick
+                }
+
+                /// Releases the TIM peripherals
+                pub fn free(self) -> ($LO, $HI) {
+                    // pause counters
+                    self.lo.cr1.modify(|_, w| w.cen().clear_bit());
+                    self.hi.cr1.modify(|_, w| w.cen().clear_bit());
+                    (self.lo, self.hi)
+                }
+            }
+        )+
+    }
+}
+
+chained! {
+    TIM3, TIM4: (tim3_tim4, tim3en, tim3rst, tim4en, tim4rst, Itr2),
+    TIM4, TIM3: (tim4_tim3, tim4en, tim4rst, tim3en, tim3rst, Itr3),
+}
+
+macro_rules! alignment {
+    ($($TIM:ident,)+) => {
+        $(
+            impl Timer<$TIM> {
+                /// Selects between edge aligned (up) and center aligned (up / down) counting
+                ///
+                /// In the center aligned modes an up / down cycle of the counter lasts twice the
+                /// auto-reload value so the PWM frequency is half the timeout frequency passed
+                /// to `start`. The update event still fires at every overflow and underflow so
+                /// `wait` keeps timing out at the requested frequency. The counter is briefly
+                /// paused as the mode can't be changed while it's running.
+                pub fn set_alignment(&mut self, alignment: Alignment) {
+                    let enabled = self.tim.cr1.read().cen().bit_is_set();
+
+                    self.tim.cr1.modify(|_, w| w.cen().clear_bit());
+                    self.tim.cr1.modify(|_, w| unsafe {
+                        w.cms().bits(alignment.bits()).dir().clear_bit()
+                    });
+                    self.tim.cr1.modify(|_, w| w.cen().bit(enabled));
+                }
+            }
+        )+
+    }
+}
+
+alignment! {
+    TIM2,
+    TIM3,
+    TIM4,
+}
+
+macro_rules! basic_master {
+    ($($TIM:ident,)+) => {
+        $(
+            impl Timer<$TIM> {
+                /// Selects what this timer sends through its TRGO output, e.g. to pace the DAC or
+                /// the ADC
+                pub fn set_master_mode(&mut self, mode: BasicMasterMode) {
+                    self.tim.cr2.modify(|_, w| unsafe { w.mms().bits(mode.bits()) });
+                }
+            }
+        )+
+    }
+}
+
+basic_master! {
+    TIM6,
+    TIM7,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pwm_resolution, timclk, Flags};
+    use time::Hertz;
+
+    #[test]
+    fn flags() {
+        // UIF, CC1IF to CC4IF, TIF and CC1OF to CC4OF, as in TIMx_SR
+        assert_eq!(Flags::all().bits(), 0b1_1110_0101_1111);
+        assert_eq!(Flags::UIF.bits(), 1 << 0);
+        assert_eq!(Flags::CC4IF.bits(), 1 << 4);
+        assert_eq!(Flags::TIF.bits(), 1 << 6);
+        assert_eq!(Flags::CC1OF.bits(), 1 << 9);
+        assert_eq!(Flags::CC4OF.bits(), 1 << 12);
+    }
+
+    #[test]
+    fn timer_clock() {
+        // bus not divided
+        assert_eq!(timclk(Hertz(72_000_000), 1).0, 72_000_000);
+        // bus divided: the timers run at twice the bus clock
+        assert_eq!(timclk(Hertz(36_000_000), 2).0, 72_000_000);
+        assert_eq!(timclk(Hertz(4_000_000), 16).0, 8_000_000);
+    }
+
+    #[test]
+    fn resolution() {
+        let timclk = Hertz(72_000_000);
+
+        assert_eq!(pwm_resolution(timclk, Hertz(1_000_000)), 72);
+        assert_eq!(pwm_resolution(timclk, Hertz(20_000)), 3_600);
+        // the prescaler kicks in below ~1.1 KHz
+        assert_eq!(pwm_resolution(timclk, Hertz(1_000)), 36_000);
+        assert_eq!(pwm_resolution(timclk, Hertz(50)), 65_454);
+
+        assert_eq!(pwm_resolution(timclk, Hertz(72_000_000)), 1);
+        assert_eq!(pwm_resolution(timclk, Hertz(100_000_000)), 0);
+    }
 }
@@ -0,0 +1,65 @@
+//! WS2812 ("NeoPixel") addressable LEDs driven from a SPI bus
+//!
+//! Only the MOSI line of the SPI bus is connected to the data input of the first LED. Every data
+//! bit is encoded as 4 SPI bits (`1000` for a zero, `1110` for a one) so the SPI bus must be
+//! configured for an SCK frequency of 3 MHz. As the SPI prescaler is a power of 2, that requires
+//! e.g. a `pclk2` of 48 MHz for `SPI1` (divided by 16) or a `pclk1` of 24 MHz for `SPI2` / `SPI3`
+//! (divided by 8). The SPI mode doesn't matter.
+//!
+//! The bytes have to be sent back to back so interrupts that fire during `write` can corrupt the
+//! frame.
+
+use hal::blocking::spi::Write;
+
+/// SPI bytes encoding two data bits each, indexed by the value of the two bits
+const PATTERNS: [u8; 4] = [0b1000_1000, 0b1000_1110, 0b1110_1000, 0b1110_1110];
+
+/// Number of zero bytes sent after a frame to latch it; about 300 us at 3 MHz
+const RESET_BYTES: usize = 120;
+
+/// Chain of WS2812 LEDs
+pub struct Ws2812<SPI> {
+    spi: SPI,
+}
+
+impl<SPI> Ws2812<SPI>
+where
+    SPI: Write<u8>,
+{
+    /// Drives a chain of LEDs connected to the MOSI line of `spi`
+    pub fn new(spi: SPI) -> Self {
+        Ws2812 { spi }
+    }
+
+    /// Sends one `(red, green, blue)` color to each LED of the chain, starting with the first one,
+    /// and then latches them
+    pub fn write(&mut self, colors: &[(u8, u8, u8)]) -> Result<(), SPI::Error> {
+        for &(red, green, blue) in colors {
+            // the LEDs expect the green component first
+            for byte in &[green, red, blue] {
+                self.write_byte(*byte)?;
+            }
+        }
+
+        for _ in 0..RESET_BYTES {
+            self.spi.write(&[0])?;
+        }
+
+        Ok(())
+    }
+
+    fn write_byte(&mut self, byte: u8) -> Result<(), SPI::Error> {
+        // MSB first
+        let mut bytes = [0; 4];
+        for (i, out) in bytes.iter_mut().enumerate() {
+            *out = PATTERNS[usize::from((byte >> (6 - 2 * i)) & 0b11)];
+        }
+
+        self.spi.write(&bytes)
+    }
+
+    /// Releases the SPI bus
+    pub fn free(self) -> SPI {
+        self.spi
+    }
+}